@@ -40,6 +40,11 @@ const NavBar: React.FC<IProps> = ({ selectedItem }) => {
             >
             <a href="/conaggxls">xlsx agg</a>
         </li>        
+        <li key="netpol"
+            className={`transition-all duration-200 rounded-full px-5 py-1 navbar-item bg-cyan-700`}
+            >
+            <a href="/conpolicy">k8s policy</a>
+        </li>
       </ol>      
       <h1 className="font-bold text-lg">NETRACER</h1>
     </nav>
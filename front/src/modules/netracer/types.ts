@@ -1,14 +1,31 @@
 export interface Address {
+  src: Array<string>,
+  dst: Array<string>,
+  protocol: string,
+  port: Array<string>,
+  vlan: number
+}
+
+export interface Tunnel {
+  kind: string,
   src: string,
   dst: string,
-  protocol: string,
-  port: string
+  id: number
 }
 
 export interface Connection {
-   time: number,
+   first_seen: number,
+   last_seen: number,
+   hits: number,
    max_speed: number,
    avg_speed: number,
+   interfaces: Array<string>,
+   tunnels: Array<Tunnel>,
+   tx_bytes: number,
+   rx_bytes: number,
+   tx_packets: number,
+   rx_packets: number,
+   active_time: number,
    addr: Address
 }
 
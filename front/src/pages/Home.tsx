@@ -42,8 +42,9 @@ const myRangeFilterFn: FilterFn<Connection> = (row: Row<Connection>, columnId: s
 
   const vals = row.getValue<string>(columnId).split(',');
   for (const v of vals) {
-     const intval = parseInt(v.replace(" ",""));
-     const inRange = (intval >= minValue) && (intval <= maxValue); 
+     // single port or range like 8000-8010
+     const [start, end] = v.replace(" ","").split('-').map((p) => parseInt(p));
+     const inRange = (start <= maxValue) && ((end ?? start) >= minValue);
      if (inRange) {
        return true;
      }
@@ -51,6 +52,16 @@ const myRangeFilterFn: FilterFn<Connection> = (row: Row<Connection>, columnId: s
   return false;
 }
 
+const formatBytes = (bytes: number) => {
+  const units = ['B', 'KB', 'MB', 'GB', 'TB'];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) {
+    bytes /= 1024;
+    i++;
+  }
+  return `${i === 0 ? bytes : bytes.toFixed(1)} ${units[i]}`;
+}
+
 interface Props {
     children?: ReactNode
 }
@@ -98,22 +109,19 @@ const Collapse = ({children}: Props) => {
 };
 
 const columns = [
-  columnHelper.accessor('addr.src', {
+  columnHelper.accessor((row) => row.addr.src.join(' '), {
+    id: 'src',
     header: () => <span>Sources</span>,
     footer: (info) => info.column.id,
-    cell: ({ getValue }) => (
+    cell: ({ row }) => (
        <span>
-        {Array.isArray(getValue().split(' ')) ? (
           <Collapse>
             <ul>
-            {(getValue().split(' ') as string[]).map((item: string, index: number) => (
+            {row.original.addr.src.map((item: string) => (
               <div key={item}>{item}</div>
             ))}
             </ul>
           </Collapse>
-        ) : (
-          (getValue() as React.ReactNode)
-        )}
       </span>
     ),
     meta: {
@@ -123,22 +131,19 @@ const columns = [
   }),
 //     cell: (info) => info.getValue(),
 
-  columnHelper.accessor('addr.dst', {
+  columnHelper.accessor((row) => row.addr.dst.join(' '), {
+    id: 'dst',
     header: () => <span>Destination</span>,
     footer: (info) => info.column.id,
-    cell: ({ getValue }) => (
+    cell: ({ row }) => (
        <span>
-        {Array.isArray(getValue().split(' ')) ? (
         <Collapse>
           <ul>
-            {(getValue().split(' ') as string[]).map((item: string, index: number) => (
-              <div key={index}>{item}</div>
+            {row.original.addr.dst.map((item: string) => (
+              <div key={item}>{item}</div>
             ))}
           </ul>
         </Collapse>
-        ) : (
-          (getValue() as React.ReactNode)
-        )}
       </span>
     ),
     meta: {
@@ -146,8 +151,9 @@ const columns = [
     }
     
   }),
-  columnHelper.accessor('addr.port', {
-    cell: (info) =>  (<Collapse>{info.getValue().toString()}</Collapse>),
+  columnHelper.accessor((row) => row.addr.port.join(', '), {
+    id: 'port',
+    cell: (info) =>  (<Collapse>{info.getValue()}</Collapse>),
     header: () => <span>Port</span>,
     footer: (info) => info.column.id,
     filterFn: myRangeFilterFn,
@@ -163,15 +169,75 @@ const columns = [
       filterVariant: 'select'
     }
   }),
-  columnHelper.accessor('time', {
+  columnHelper.accessor('addr.vlan', {
+    cell: (info) => (info.getValue() ? info.getValue() : ''),
+    header: () => <span>VLAN</span>,
+    footer: (info) => info.column.id,
+    meta: {
+      filterVariant: 'select'
+    }
+  }),
+  columnHelper.accessor((row) => row.interfaces.join(' '), {
+    id: 'interfaces',
+    cell: (info) => info.getValue(),
+    header: () => <span>Interfaces</span>,
+    footer: (info) => info.column.id,
+    meta: {
+      filterVariant: 'text'
+    }
+  }),
+  columnHelper.accessor((row) => row.tunnels.map((t) => `${t.kind} ${t.src} > ${t.dst} id ${t.id}`).join('\n'), {
+    id: 'tunnels',
+    cell: (info) => (<Collapse>{info.getValue()}</Collapse>),
+    header: () => <span>Tunnels</span>,
+    footer: (info) => info.column.id,
+    meta: {
+      filterVariant: 'text'
+    }
+  }),
+  columnHelper.accessor((row) => row.tx_bytes + row.rx_bytes, {
+    id: 'traffic',
+    cell: ({ row }) => (
+      <span title={`${row.original.tx_packets} / ${row.original.rx_packets} packets`}>
+        {formatBytes(row.original.tx_bytes)} / {formatBytes(row.original.rx_bytes)}
+      </span>
+    ),
+    header: () => <span>Sent / Received</span>,
+    footer: (info) => info.column.id,
+    enableColumnFilter: false
+  }),
+  columnHelper.accessor('max_speed', {
+    cell: ({ row }) => (
+      <span>{formatBytes(row.original.avg_speed)}/s / {formatBytes(row.original.max_speed)}/s</span>
+    ),
+    header: () => <span>Avg / Max speed</span>,
+    footer: (info) => info.column.id,
+    enableColumnFilter: false
+  }),
+  columnHelper.accessor('first_seen', {
+    cell: (info) => new Date(info.getValue()*1000).toLocaleString(),
+    header: () => <span>First seen</span>,
+    footer: (info) => info.column.id,
+    filterFn: myDateFilterFn,
+    meta: {
+      filterVariant: 'datetime'
+    }
+  }),
+  columnHelper.accessor('last_seen', {
     cell: (info) => new Date(info.getValue()*1000).toLocaleString(),
-    header: () => <span>LastTime</span>,
+    header: () => <span>Last seen</span>,
     footer: (info) => info.column.id,
     filterFn: myDateFilterFn,
     meta: {
       filterVariant: 'datetime'
     }
   }),
+  columnHelper.accessor('hits', {
+    cell: (info) => info.getValue(),
+    header: () => <span>Hits</span>,
+    footer: (info) => info.column.id,
+    enableColumnFilter: false
+  }),
 ];
 
 const Home = () => {
@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use ctrlc;
 extern crate pcap;
 extern crate pnet;
@@ -7,28 +7,37 @@ extern crate chrono;
 use chrono::prelude::DateTime;
 use chrono::Utc;
 
-use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ethernet::{EtherType, EtherTypes};
 use pnet::packet::ethernet::EthernetPacket;
-use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
 use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::vlan::VlanPacket;
 use pnet::packet::Packet;
 
 
 use native_db::{Models, Builder, Database};
+use native_model::Model;
 use native_db::transaction::query::PrimaryScanIterator;
 
-use std::time::{SystemTime};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use include_dir::{include_dir, Dir};
 
 use rust_xlsxwriter::*;
+use serde::{Deserialize, Serialize};
 
 use actix_cors::Cors;
-use actix_web::{rt, get, App, HttpRequest, HttpResponse, HttpServer, Responder, 
+use actix_web::{rt, delete, get, put, web, App, HttpRequest, HttpResponse, HttpServer, Responder, 
                 dev::ServiceRequest, error::ErrorUnauthorized, Error as ActixError, middleware::Condition};
-use actix_files::NamedFile;
-use actix_web::http::header::{ContentDisposition, DispositionType};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
 use actix_web_httpauth::{extractors::basic::BasicAuth, middleware::HttpAuthentication};
 
 use std::process;
@@ -39,11 +48,28 @@ use rcgen::{generate_simple_self_signed, CertifiedKey};
 static UI_DIR: Dir<'_> = include_dir!("ntfront");
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    /// Network interface to listen on (e.g. eth0)
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Directory for the database
+    #[arg(long, global = true, default_value = "/var")]
+    state_dir: PathBuf,
+    /// Database file [default: <STATE_DIR>/netracer.ndb]
+    #[arg(long, global = true)]
+    db: Option<PathBuf>,
+    /// Serve a snapshot of an existing database taken at startup, without capturing. The original file is only copied, never opened or locked
+    #[arg(long, default_value_t = false, conflicts_with_all = ["interface", "read", "stdin"])]
+    read_only: bool,
+    /// Network interfaces to listen on (e.g. eth0). Can be repeated or comma separated, each interface is captured in its own thread
+    #[arg(short, long, value_delimiter = ',', required_unless_present_any = ["read", "stdin", "read_only"])]
+    interface: Vec<String>,
+    /// Read packets from a pcap/pcapng file, alongside the live capture of --interface if given. Can be repeated.
     #[arg(short, long)]
-    interface: String,
+    read: Vec<String>,
+    /// Read a pcap stream from stdin (e.g. ssh host tcpdump -U -w - | netracer --stdin). Named pipes can be passed to --read.
+    #[arg(long, default_value_t = false)]
+    stdin: bool,
     /// Listens to HTTPS on port tcp/3095. If not specified, then HTTP is listened to on the same port.)
     #[arg(short, long, default_value_t = false)]
     tls: bool,
@@ -56,6 +82,54 @@ struct Args {
     /// Enables basic authentication by name and password. Specify the path to the file created using htpasswd.
     #[arg(short, long)]
     authfile: Option<String>,
+    /// Enable DELETE /con and PUT /admin/filter without --authfile. Anyone reaching the port can remove connections and change the filter
+    #[arg(long, default_value_t = false)]
+    allow_changes: bool,
+    /// BPF capture filter applied in the kernel (e.g. "not port 873"). Can be changed at runtime with PUT /admin/filter
+    #[arg(short, long)]
+    filter: Option<String>,
+    /// Put the interfaces into promiscuous mode
+    #[arg(short, long, default_value_t = false)]
+    promisc: bool,
+    /// Decapsulate VXLAN (udp/4789), Geneve (udp/6081) and GRE tunnels and register the inner connections too
+    #[arg(long, default_value_t = false)]
+    decap: bool,
+    /// Window (seconds) the connection speeds are measured over
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    speed_window: u64,
+    /// Interval (seconds) the traffic counters are written to the database
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    flush_interval: u64,
+    /// Network shown instead of the addresses inside it in collapsed aggregations (e.g. 10.20.0.0/16). Can be repeated
+    #[arg(long)]
+    network: Vec<Subnet>,
+    /// Minimum number of ports written as a range (8000-8010) in aggregations, 0 lists every port
+    #[arg(long, default_value_t = 3)]
+    port_range_min: u16,
+    /// Maximum distance between neighbouring ports of a range, 1 joins only consecutive ports
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    port_range_gap: u16,
+    /// Timezone of the dates in XLSX reports: utc, local or an offset like +03:00
+    #[arg(long, default_value = "utc")]
+    timezone: ReportTimezone,
+    /// Maximum number of connection events waiting for the database writer, further events of live captures are dropped
+    #[arg(long, default_value_t = 65536, value_parser = clap::value_parser!(u64).range(1..))]
+    queue_size: u64,
+    /// Interval (milliseconds) new connections are committed to the database
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    commit_interval: u64,
+    /// Remove connections not seen for this many days
+    #[arg(long)]
+    retention_days: Option<u64>,
+    /// Retention period for a protocol, overrides --retention-days (e.g. udp=7). Can be repeated
+    #[arg(long, value_parser = parse_protocol_days)]
+    retention_protocol: Vec<(data::Protocol, u64)>,
+    /// Keep at most this many connections, the least recently seen are removed
+    #[arg(long)]
+    max_entries: Option<usize>,
+    /// Interval (seconds) the retention policy is applied
+    #[arg(long, default_value_t = 3600, value_parser = clap::value_parser!(u64).range(1..))]
+    prune_interval: u64,
     /// UDP Connections to destination ports above this will not be registered (for example filter out IANA private ports -d 49152)
     #[arg(short='d', long, default_value_t = 65535)]
     max_dst_udp_port: u16,
@@ -64,227 +138,3161 @@ struct Args {
     min_src_udp_port: u16
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Upgrade the database to the current model version and exit
+    Migrate {
+        /// Only report what would change, the check runs on a copy of the database
+        #[arg(long, default_value_t = false)]
+        check: bool
+    }
+}
+
+/// Packet processing options shared by live and offline captures
+#[derive(Clone)]
+struct CaptureOptions {
+    /// Interface or input name recorded in the connections
+    interface: String,
+    /// Reading a file or stdin: the writer is waited for instead of dropping events
+    offline: bool,
+    /// Decapsulate VXLAN, Geneve and GRE
+    decap: bool,
+    max_dst_udp_port: u16,
+    min_src_udp_port: u16
+}
+
+
+pub mod data {
+    use native_db::{native_db, ToKey, Key};
+    use native_model::{native_model, Model};
+    use serde::{Deserialize, Serialize};
+
+    pub type Connection = v2::Connection;
+    pub(crate) type ConnectionKey = v2::ConnectionKey;
+    pub type Address = v2::Address;
+    pub type Tunnel = v2::Tunnel;
+    pub type Protocol = v2::Protocol;
+
+    pub mod v1 {
+        use super::*;         
+        #[derive(Debug, Deserialize, Serialize, Clone)]
+        pub struct Address {
+          pub src: String,
+          pub dst: String,      
+          pub protocol: String,    
+          pub port: String
+        }
+
+        impl ToKey for Address {
+           fn to_key(&self) -> Key {
+             let keystr = format!("{}_{}_{}_{}",self.src, self.dst, self.protocol, self.port);
+             Key::new(keystr.as_bytes().to_vec())
+           }
+
+           fn key_names() -> Vec<String> {
+             vec!["Address".to_string()]
+           }
+        }
+
+
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        #[native_model(id = 1, version = 1)]
+        #[native_db]
+        pub struct Connection {
+          #[primary_key]
+          pub addr: Address,
+          pub time: u64,
+          pub max_speed: u32,
+          pub avg_speed: u32
+        }
+    }
+
+    pub mod v2 {
+        use super::*;
+        use std::fmt;
+        use std::net::{IpAddr, Ipv4Addr};
+        use std::str::FromStr;
+
+        /// IP protocol number. Serialized as a name ("TCP", "UDP", "GRE") in JSON, as a byte in the database
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct Protocol(pub u8);
+
+        impl Protocol {
+          pub const TCP: Protocol = Protocol(6);
+          pub const UDP: Protocol = Protocol(17);
+          pub const GRE: Protocol = Protocol(47);
+        }
+
+        impl fmt::Display for Protocol {
+          fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+              Protocol::TCP => write!(f, "TCP"),
+              Protocol::UDP => write!(f, "UDP"),
+              Protocol::GRE => write!(f, "GRE"),
+              Protocol(n) => write!(f, "{}", n)
+            }
+          }
+        }
+
+        impl FromStr for Protocol {
+          type Err = String;
+
+          fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s.to_uppercase().as_str() {
+              "TCP" => Ok(Protocol::TCP),
+              "UDP" => Ok(Protocol::UDP),
+              "GRE" => Ok(Protocol::GRE),
+              n => n.parse().map(Protocol).map_err(|_| format!("unknown protocol {}", s))
+            }
+          }
+        }
+
+        impl Serialize for Protocol {
+          fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if serializer.is_human_readable() {
+              serializer.collect_str(self)
+            } else {
+              serializer.serialize_u8(self.0)
+            }
+          }
+        }
+
+        impl<'de> Deserialize<'de> for Protocol {
+          fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            if deserializer.is_human_readable() {
+              let s = String::deserialize(deserializer)?;
+              s.parse().map_err(serde::de::Error::custom)
+            } else {
+              u8::deserialize(deserializer).map(Protocol)
+            }
+          }
+        }
+
+        #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+        pub struct Address {
+          pub src: IpAddr,
+          pub dst: IpAddr,
+          pub protocol: Protocol,
+          /// Destination port, 0 for protocols without ports
+          pub port: u16,
+          /// 802.1Q VLAN ID, 0 for untagged frames
+          pub vlan: u16
+        }
+
+        /// Address family byte followed by 16 address bytes (IPv4 as IPv4-mapped IPv6),
+        /// so keys of the same family sort numerically and IPv4 never collides with IPv6
+        pub fn ip_key(ip: &IpAddr, key: &mut Vec<u8>) {
+          match ip {
+            IpAddr::V4(ip) => {
+              key.push(4);
+              key.extend_from_slice(&ip.to_ipv6_mapped().octets());
+            },
+            IpAddr::V6(ip) => {
+              key.push(6);
+              key.extend_from_slice(&ip.octets());
+            }
+          }
+        }
+
+        impl ToKey for Address {
+           /// src (17 bytes), dst (17 bytes), protocol, port and VLAN big endian: 39 bytes
+           fn to_key(&self) -> Key {
+             let mut key = Vec::with_capacity(39);
+             ip_key(&self.src, &mut key);
+             ip_key(&self.dst, &mut key);
+             key.push(self.protocol.0);
+             key.extend_from_slice(&self.port.to_be_bytes());
+             key.extend_from_slice(&self.vlan.to_be_bytes());
+             Key::new(key)
+           }
+
+           fn key_names() -> Vec<String> {
+             vec!["Address".to_string()]
+           }
+        }
+
+        /// Outer endpoints of the tunnel an inner connection was seen in
+        #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+        pub struct Tunnel {
+          /// VXLAN, GENEVE or GRE
+          pub kind: String,
+          pub src: IpAddr,
+          pub dst: IpAddr,
+          /// VNI for VXLAN/Geneve, key for GRE (0 if absent)
+          pub id: u32
+        }
+
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        #[native_model(id = 1, version = 2, from = v1::Connection)]
+        #[native_db(secondary_key(dst_key -> Vec<u8>), secondary_key(port_key -> u16))]
+        pub struct Connection {
+          #[primary_key]
+          pub addr: Address,
+          /// Time of the first packet (seconds since epoch)
+          pub first_seen: u64,
+          /// Time of the last packet in either direction
+          #[secondary_key]
+          pub last_seen: u64,
+          /// Number of sessions: TCP SYNs, UDP flows starting after an idle period
+          pub hits: u64,
+          /// Maximum throughput over a speed window (bytes/s)
+          pub max_speed: u32,
+          /// Average throughput over the windows with traffic (bytes/s)
+          pub avg_speed: u32,
+          /// Interfaces (or input files) the connection was seen on
+          pub interfaces: Vec<String>,
+          /// Tunnels the connection was decapsulated from, empty for plain traffic
+          pub tunnels: Vec<Tunnel>,
+          /// Bytes sent from source to destination
+          pub tx_bytes: u64,
+          /// Bytes sent from destination back to source
+          pub rx_bytes: u64,
+          pub tx_packets: u64,
+          pub rx_packets: u64,
+          /// Total length of the speed windows with traffic (seconds)
+          pub active_time: u64
+        }
+
+        impl Connection {
+          /// Destination address encoded like in the primary key, networks are key ranges
+          pub fn dst_key(&self) -> Vec<u8> {
+            let mut key = Vec::with_capacity(17);
+            ip_key(&self.addr.dst, &mut key);
+            key
+          }
+
+          pub fn port_key(&self) -> u16 {
+            self.addr.port
+          }
+        }
+
+        /// Old records always hold addresses written by `IpAddr::to_string`
+        fn parse_ip(ip: &str) -> IpAddr {
+          ip.parse().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        }
+
+        impl From<v1::Connection> for Connection {
+          fn from(con: v1::Connection) -> Self {
+            Connection {
+              addr: Address {
+                src: parse_ip(&con.addr.src),
+                dst: parse_ip(&con.addr.dst),
+                protocol: con.addr.protocol.parse().unwrap_or(Protocol(0)),
+                port: con.addr.port.parse().unwrap_or(0),
+                vlan: 0
+              },
+              first_seen: con.time,
+              last_seen: con.time,
+              hits: 1,
+              max_speed: con.max_speed,
+              avg_speed: con.avg_speed,
+              interfaces: Vec::new(),
+              tunnels: Vec::new(),
+              tx_bytes: 0,
+              rx_bytes: 0,
+              tx_packets: 0,
+              rx_packets: 0,
+              active_time: 0
+            }
+          }
+        }
+
+        impl From<Connection> for v1::Connection {
+          fn from(con: Connection) -> Self {
+            v1::Connection {
+              addr: v1::Address {
+                src: con.addr.src.to_string(),
+                dst: con.addr.dst.to_string(),
+                protocol: con.addr.protocol.to_string(),
+                port: con.addr.port.to_string()
+              },
+              time: con.first_seen,
+              max_speed: con.max_speed,
+              avg_speed: con.avg_speed
+            }
+          }
+        }
+    }
+}
+
+use once_cell::sync::{Lazy, OnceCell};
+
+/// Per-packet data collected while decapsulating the frame
+#[derive(Clone, Copy)]
+struct PacketMeta {
+    /// Capture timestamp (seconds since epoch)
+    time: u64,
+    /// Length of the captured frame on the wire
+    len: u32,
+    /// Innermost VLAN ID, 0 if the frame is untagged
+    vlan: u16,
+    /// Innermost tunnel the packet was decapsulated from
+    tunnel: Option<TunnelInfo>,
+    /// Number of tunnels the packet was decapsulated from
+    depth: u8
+}
+
+#[derive(Clone, Copy)]
+struct TunnelInfo {
+    kind: &'static str,
+    src: IpAddr,
+    dst: IpAddr,
+    id: u32
+}
+
+impl TunnelInfo {
+    fn record(&self) -> data::Tunnel {
+      data::Tunnel {
+        kind: self.kind.to_string(),
+        src: self.src,
+        dst: self.dst,
+        id: self.id
+      }
+    }
+}
+
+/// Current BPF capture filter, empty string captures everything
+static CAPTURE_FILTER: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
+/// Incremented on every filter change so the capture threads reapply it
+static CAPTURE_FILTER_VERSION: AtomicU64 = AtomicU64::new(0);
+/// Interface and link type of every running capture, a new filter has to compile for all of them
+static OPEN_CAPTURES: Lazy<Mutex<HashMap<u64, (String, pcap::Linktype)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_CAPTURE_ID: AtomicU64 = AtomicU64::new(0);
+
+static MODELS: Lazy<Models> = Lazy::new(|| {
+   let mut models = Models::new();
+   // It's a good practice to define the models by specifying the version
+   models.define::<data::v1::Connection>().unwrap();
+   models.define::<data::v2::Connection>().unwrap();
+   models
+});
+
+/// Database file (--db), set before DB is first used
+static DB_PATH: OnceCell<PathBuf> = OnceCell::new();
+/// Database is served without capturing or modifying it (--read-only)
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+static DB: Lazy<Database> = Lazy::new(|| {
+  let path = DB_PATH.get().unwrap();
+  // redb opens the file read-write, locks it and creates missing tables, so --read-only serves a snapshot
+  if READ_ONLY.load(Ordering::Relaxed) {
+    return open_snapshot(path);
+  }
+  match Builder::new().create(&MODELS, path) {
+    Ok(db) => db,
+    Err(e) => {
+      println!("Can't open database {}: {}", path.display(), e);
+      process::exit(1);
+    }
+  }
+});
+
+/// Opens a private copy of an existing database, the original file is never opened by redb
+fn open_snapshot(path: &Path) -> Database<'static> {
+  let copy = std::env::temp_dir().join(format!("netracer-snapshot-{}.ndb", process::id()));
+  if let Err(e) = std::fs::copy(path, &copy) {
+    println!("Can't copy database {}: {}", path.display(), e);
+    process::exit(1);
+  }
+  let db = Builder::new().open(&MODELS, &copy);
+  // the open file stays readable, the copy disappears when the process exits
+  let _ = std::fs::remove_file(&copy);
+  match db {
+    Ok(db) => db,
+    Err(e) => {
+      println!("Can't open database {}: {}", path.display(), e);
+      process::exit(1);
+    }
+  }
+}
+
+/// Sets the state directory and the database path from the command line
+fn init_paths(args: &Args) {
+    let db_path = args.db.clone().unwrap_or_else(|| args.state_dir.join("netracer.ndb"));
+    if !args.read_only && let Err(e) = std::fs::create_dir_all(&args.state_dir) {
+      println!("Can't create state directory {}: {}", args.state_dir.display(), e);
+      process::exit(1);
+    }
+    NETWORKS.set(args.network.clone()).unwrap();
+    PORT_RANGES.set(PortRanges { min: args.port_range_min, gap: args.port_range_gap }).unwrap();
+    TIMEZONE.set(args.timezone).unwrap();
+    DB_PATH.set(db_path).unwrap();
+    READ_ONLY.store(args.read_only, Ordering::Relaxed);
+}
+
+/// What every model version changed, reported by `migrate --check`
+const MODEL_CHANGES: &[(u32, &str)] = &[
+    (2, "stores typed addresses with the VLAN ID in the key, adds interfaces, tunnels, byte/packet counters, first/last seen time, hits and indexes"),
+];
+
+/// Number of stored connections for every model version
+fn count_records(db: &Database) -> Vec<(u32, u64)> {
+    let r = db.r_transaction().unwrap();
+    vec![
+      (1, r.len().primary::<data::v1::Connection>().unwrap_or(0)),
+      (2, r.len().primary::<data::v2::Connection>().unwrap_or(0)),
+    ]
+}
+
+/// Exits if the database served with --read-only holds connections of older model versions
+fn check_db_version(db: &Database) {
+    let current = data::Connection::native_model_version();
+    if count_records(db).iter().any(|(version, count)| *version != current && *count > 0) {
+      println!("Database {} needs migration, run netracer migrate --db {}",
+               DB_PATH.get().unwrap().display(), DB_PATH.get().unwrap().display());
+      process::exit(1);
+    }
+}
+
+/// Upgrades the stored connections to the current model version in place
+fn migrate_db(db: &Database) {
+    let current = data::Connection::native_model_version();
+    for (version, count) in count_records(db) {
+      if version != current && count > 0 {
+        println!("Migrating {} connections from v{} to v{}", count, version, current);
+      }
+    }
+    let rw = db.rw_transaction().unwrap();
+    let result = match rw.migrate::<data::Connection>() {
+      Ok(_) => rw.commit(),
+      Err(e) => Err(e)
+    };
+    if let Err(e) = result {
+      println!("Can't migrate database: {}", e);
+      process::exit(1);
+    }
+}
+
+/// `netracer migrate [--check]`
+fn run_migrate(check: bool) {
+    let path = DB_PATH.get().unwrap();
+    let db = if check {
+      open_snapshot(path)
+    } else {
+      match Builder::new().open(&MODELS, path) {
+        Ok(db) => db,
+        Err(e) => {
+          println!("Can't open {}: {}", path.display(), e);
+          process::exit(1);
+        }
+      }
+    };
+    let current = data::Connection::native_model_version();
+    println!("Database {}, current model version v{}", path.display(), current);
+    let mut outdated = false;
+    for (version, count) in count_records(&db) {
+      if count == 0 {
+        continue;
+      }
+      if version == current {
+        println!("v{}: {} connections, up to date", version, count);
+        continue;
+      }
+      outdated = true;
+      println!("v{}: {} connections would be migrated to v{}:", version, count, current);
+      for (change_version, change) in MODEL_CHANGES {
+        if *change_version > version && *change_version <= current {
+          println!("  v{}: {}", change_version, change);
+        }
+      }
+    }
+    if !outdated {
+      println!("Nothing to migrate");
+    } else if !check {
+      migrate_db(&db);
+      println!("Migration finished");
+    }
+}
+        
+
+#[get("/{filename:.*}")]
+async fn mainpage(req: HttpRequest) -> impl Responder {
+    let mut path = req.match_info().query("filename");
+    println!("{}",path);
+    if path == "" {
+       path = "index.html";
+    }    
+    let file = UI_DIR.get_file(path).unwrap();
+//    let body = file.contents_utf8().unwrap();
+    let body = file.contents();
+    HttpResponse::Ok().body(body)
+}
+
+/// Parses seconds since epoch, an RFC 3339 time (2024-05-01T10:00:00Z) or a date (2024-05-01, UTC midnight)
+fn parse_time(s: &str) -> Result<u64, String> {
+    if let Ok(time) = s.parse() {
+      return Ok(time);
+    }
+    let time = match DateTime::parse_from_rfc3339(s) {
+      Ok(time) => time.timestamp(),
+      Err(_) => match chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp(),
+        Err(_) => return Err(format!("invalid time {}", s))
+      }
+    };
+    u64::try_from(time).map_err(|_| format!("invalid time {}", s))
+}
+
+fn deserialize_time<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+      Some(s) => parse_time(&s).map(Some).map_err(serde::de::Error::custom),
+      None => Ok(None)
+    }
+}
+
+/// Query parameters accepted by the connection endpoints
+#[derive(Deserialize, Debug)]
+struct ConQuery {
+    /// Only connections seen on this interface
+    interface: Option<String>,
+    /// Source address or network (10.0.0.0/8)
+    src: Option<Subnet>,
+    /// Destination address or network
+    dst: Option<Subnet>,
+    /// TCP, UDP, GRE or the protocol number
+    protocol: Option<data::Protocol>,
+    /// Port or range of ports (8000-8100)
+    port: Option<PortRange>,
+    vlan: Option<u16>,
+    /// Time window of the first and the last packet, `after` is inclusive, `before` exclusive
+    #[serde(default, deserialize_with = "deserialize_time")]
+    first_seen_after: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_time")]
+    first_seen_before: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_time")]
+    last_seen_after: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_time")]
+    last_seen_before: Option<u64>
+}
+
+impl ConQuery {
+    fn matches(&self, con: &data::Connection) -> bool {
+      self.interface.as_ref().is_none_or(|interface| con.interfaces.contains(interface)) &&
+      self.src.is_none_or(|src| src.contains(con.addr.src)) &&
+      self.dst.is_none_or(|dst| dst.contains(con.addr.dst)) &&
+      self.protocol.is_none_or(|protocol| con.addr.protocol == protocol) &&
+      self.port.is_none_or(|port| port.contains(con.addr.port)) &&
+      self.vlan.is_none_or(|vlan| con.addr.vlan == vlan) &&
+      self.first_seen_after.is_none_or(|time| con.first_seen >= time) &&
+      self.first_seen_before.is_none_or(|time| con.first_seen < time) &&
+      self.last_seen_after.is_none_or(|time| con.last_seen >= time) &&
+      self.last_seen_before.is_none_or(|time| con.last_seen < time)
+    }
+
+    /// True if no filter is set and every connection matches
+    fn is_empty(&self) -> bool {
+      self.interface.is_none() && self.src.is_none() && self.dst.is_none() &&
+      self.protocol.is_none() && self.port.is_none() && self.vlan.is_none() &&
+      self.first_seen_after.is_none() && self.first_seen_before.is_none() &&
+      self.last_seen_after.is_none() && self.last_seen_before.is_none()
+    }
+}
+
+/// Field the connections are sorted by
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum SortField {
+    Src,
+    Dst,
+    Protocol,
+    Port,
+    Vlan,
+    FirstSeen,
+    LastSeen,
+    Hits,
+    /// Bytes in both directions
+    Bytes,
+    TxBytes,
+    RxBytes,
+    /// Packets in both directions
+    Packets,
+    MaxSpeed,
+    AvgSpeed
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc
+}
+
+/// Value a connection is sorted by
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    Number(u64),
+    Subnet(Option<Subnet>),
+    Port(Option<PortRange>)
+}
+
+/// Rows of the connection endpoints: stored and aggregated connections
+trait Sortable {
+    fn sort_value(&self, field: SortField) -> SortValue;
+}
+
+impl Sortable for data::Connection {
+    fn sort_value(&self, field: SortField) -> SortValue {
+      match field {
+        SortField::Src => SortValue::Subnet(Some(Subnet::host(self.addr.src))),
+        SortField::Dst => SortValue::Subnet(Some(Subnet::host(self.addr.dst))),
+        SortField::Protocol => SortValue::Number(self.addr.protocol.0 as u64),
+        SortField::Port => SortValue::Port(Some(PortRange::single(self.addr.port))),
+        SortField::Vlan => SortValue::Number(self.addr.vlan as u64),
+        SortField::FirstSeen => SortValue::Number(self.first_seen),
+        SortField::LastSeen => SortValue::Number(self.last_seen),
+        SortField::Hits => SortValue::Number(self.hits),
+        SortField::Bytes => SortValue::Number(self.tx_bytes + self.rx_bytes),
+        SortField::TxBytes => SortValue::Number(self.tx_bytes),
+        SortField::RxBytes => SortValue::Number(self.rx_bytes),
+        SortField::Packets => SortValue::Number(self.tx_packets + self.rx_packets),
+        SortField::MaxSpeed => SortValue::Number(self.max_speed as u64),
+        SortField::AvgSpeed => SortValue::Number(self.avg_speed as u64)
+      }
+    }
+}
+
+impl Sortable for AggConnection {
+    /// Lists are sorted by their first (lowest) value
+    fn sort_value(&self, field: SortField) -> SortValue {
+      match field {
+        SortField::Src => SortValue::Subnet(self.addr.src.first().copied()),
+        SortField::Dst => SortValue::Subnet(self.addr.dst.first().copied()),
+        SortField::Protocol => SortValue::Number(self.addr.protocol.0 as u64),
+        SortField::Port => SortValue::Port(self.addr.port.first().copied()),
+        SortField::Vlan => SortValue::Number(self.addr.vlan as u64),
+        SortField::FirstSeen => SortValue::Number(self.first_seen),
+        SortField::LastSeen => SortValue::Number(self.last_seen),
+        SortField::Hits => SortValue::Number(self.hits),
+        SortField::Bytes => SortValue::Number(self.tx_bytes + self.rx_bytes),
+        SortField::TxBytes => SortValue::Number(self.tx_bytes),
+        SortField::RxBytes => SortValue::Number(self.rx_bytes),
+        SortField::Packets => SortValue::Number(self.tx_packets + self.rx_packets),
+        SortField::MaxSpeed => SortValue::Number(self.max_speed as u64),
+        SortField::AvgSpeed => SortValue::Number(self.avg_speed as u64)
+      }
+    }
+}
+
+/// Sorting and pagination parameters of the connection endpoints
+#[derive(Deserialize, Debug)]
+struct PageQuery {
+    sort: Option<SortField>,
+    #[serde(default)]
+    order: SortOrder,
+    /// Maximum number of rows returned
+    limit: Option<usize>,
+    /// Number of rows skipped
+    offset: Option<usize>
+}
+
+impl PageQuery {
+    /// Sorts the rows and keeps the requested page, returns the number of rows before paging
+    fn apply<T: Sortable>(&self, rows: &mut Vec<T>) -> usize {
+      let total = rows.len();
+      if let Some(field) = self.sort {
+        rows.sort_by_cached_key(|row| row.sort_value(field));
+        if self.order == SortOrder::Desc {
+          rows.reverse();
+        }
+      }
+      rows.drain(..std::cmp::min(self.offset.unwrap_or(0), total));
+      if let Some(limit) = self.limit {
+        rows.truncate(limit);
+      }
+      total
+    }
+}
+
+/// Passes the connections matching the query to `f` until it returns false. Queries by destination,
+/// port or last seen time scan the index range, the rest of the filters is checked on the scanned connections.
+fn scan_connections(query: &ConQuery, mut f: impl FnMut(data::Connection) -> bool) {
+    let r = DB.r_transaction().unwrap();
+    let mut add = |values: &mut dyn Iterator<Item = data::Connection>| {
+      for c in values {
+        if query.matches(&c) && !f(c) {
+          break;
+        }
+      }
+    };
+    if let Some(dst) = query.dst {
+      let scan = r.scan().secondary::<data::Connection>(data::ConnectionKey::dst_key).unwrap();
+      let (mut first, mut last) = (Vec::with_capacity(17), Vec::with_capacity(17));
+      data::v2::ip_key(&dst.addr, &mut first);
+      data::v2::ip_key(&dst.last(), &mut last);
+      add(&mut scan.range(first..=last).unwrap().flatten());
+    } else if let Some(port) = query.port {
+      let scan = r.scan().secondary::<data::Connection>(data::ConnectionKey::port_key).unwrap();
+      add(&mut scan.range(port.start..=port.end).unwrap().flatten());
+    } else if query.last_seen_after.is_some() || query.last_seen_before.is_some() {
+      let start = query.last_seen_after.unwrap_or(0);
+      let end = query.last_seen_before.unwrap_or(u64::MAX);
+      if start < end {
+        let scan = r.scan().secondary::<data::Connection>(data::ConnectionKey::last_seen).unwrap();
+        add(&mut scan.range(start..end).unwrap().flatten());
+      }
+    } else {
+      let binding = r.scan().primary().unwrap();
+      let values: PrimaryScanIterator<data::Connection> = binding.all().unwrap();
+      add(&mut values.flatten());
+    }
+}
+
+fn get_connections(query: &ConQuery) -> Vec<data::Connection> {
+    let mut cons: Vec<data::Connection> = Vec::new();
+    scan_connections(query, |c| {
+      cons.push(c);
+      true
+    });
+    cons
+}
+
+/// Adds missing items (interfaces, tunnels) of the merged connection, returns true if anything was added
+fn merge_list<T: PartialEq + Clone>(list: &mut Vec<T>, other: &[T]) -> bool {
+    let mut changed = false;
+    for item in other {
+      if !list.contains(item) {
+        list.push(item.clone());
+        changed = true;
+      }
+    }
+    changed
+}
+
+/// IP network, a single host when the prefix covers the whole address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Subnet {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl Subnet {
+    fn max_prefix(addr: IpAddr) -> u8 {
+      if addr.is_ipv4() { 32 } else { 128 }
+    }
+
+    fn host(addr: IpAddr) -> Subnet {
+      Subnet { addr, prefix: Subnet::max_prefix(addr) }
+    }
+
+    /// Network of the given length the address belongs to
+    fn new(addr: IpAddr, prefix: u8) -> Subnet {
+      let prefix = std::cmp::min(prefix, Subnet::max_prefix(addr));
+      let addr = match addr {
+        IpAddr::V4(ip) => {
+          let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+          IpAddr::V4((u32::from(ip) & mask).into())
+        },
+        IpAddr::V6(ip) => {
+          let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+          IpAddr::V6((u128::from(ip) & mask).into())
+        }
+      };
+      Subnet { addr, prefix }
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+      addr.is_ipv4() == self.addr.is_ipv4() && Subnet::new(addr, self.prefix).addr == self.addr
+    }
+
+    /// Last address of the network
+    fn last(&self) -> IpAddr {
+      match self.addr {
+        IpAddr::V4(ip) => {
+          let hosts = u32::MAX.checked_shr(self.prefix as u32).unwrap_or(0);
+          IpAddr::V4((u32::from(ip) | hosts).into())
+        },
+        IpAddr::V6(ip) => {
+          let hosts = u128::MAX.checked_shr(self.prefix as u32).unwrap_or(0);
+          IpAddr::V6((u128::from(ip) | hosts).into())
+        }
+      }
+    }
+
+    fn contains_subnet(&self, other: &Subnet) -> bool {
+      other.prefix >= self.prefix && self.contains(other.addr)
+    }
+
+    /// Both halves of the same network
+    fn is_sibling(&self, other: &Subnet) -> bool {
+      self.prefix == other.prefix && self.prefix > 0 && self != other &&
+        Subnet::new(self.addr, self.prefix - 1) == Subnet::new(other.addr, other.prefix - 1)
+    }
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+      if self.prefix == Subnet::max_prefix(self.addr) {
+        write!(f, "{}", self.addr)
+      } else {
+        write!(f, "{}/{}", self.addr, self.prefix)
+      }
+    }
+}
+
+impl std::str::FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+      let (addr, prefix) = match s.trim().split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s.trim(), None)
+      };
+      let addr: IpAddr = addr.parse().map_err(|_| format!("invalid address {}", s))?;
+      let prefix = match prefix {
+        Some(prefix) => match prefix.parse() {
+          Ok(prefix) if prefix <= Subnet::max_prefix(addr) => prefix,
+          _ => return Err(format!("invalid prefix length in {}", s))
+        },
+        None => Subnet::max_prefix(addr)
+      };
+      Ok(Subnet::new(addr, prefix))
+    }
+}
+
+impl Serialize for Subnet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+      serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Subnet {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+      String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Replaces the subnets with the smallest set of prefixes covering the same addresses
+fn collapse_subnets(list: &mut Vec<Subnet>) {
+    list.sort();
+    list.dedup();
+    let mut result: Vec<Subnet> = Vec::with_capacity(list.len());
+    for subnet in list.drain(..) {
+      // a containing network is sorted before the networks inside it
+      if let Some(last) = result.last() && last.contains_subnet(&subnet) {
+        continue;
+      }
+      result.push(subnet);
+      while result.len() >= 2 && result[result.len() - 2].is_sibling(&result[result.len() - 1]) {
+        let last = result.pop().unwrap();
+        result.pop();
+        result.push(Subnet::new(last.addr, last.prefix - 1));
+      }
+    }
+    *list = result;
+}
+
+/// User defined networks (--network), addresses inside them are shown as the network
+static NETWORKS: OnceCell<Vec<Subnet>> = OnceCell::new();
+
+/// How addresses of the aggregated connections are collapsed into networks
+#[derive(Debug, Default)]
+struct Collapse {
+    /// IPv4 addresses are widened to networks of this length
+    prefix4: u8,
+    /// IPv6 addresses are widened to networks of this length
+    prefix6: u8,
+    /// Networks replacing the addresses inside them, the most specific one wins
+    networks: Vec<Subnet>
+}
+
+impl Collapse {
+    fn subnet(&self, addr: IpAddr) -> Subnet {
+      let network = self.networks.iter()
+        .filter(|n| n.contains(addr))
+        .max_by_key(|n| n.prefix);
+      match network {
+        Some(network) => *network,
+        None if addr.is_ipv4() => Subnet::new(addr, self.prefix4),
+        None => Subnet::new(addr, self.prefix6)
+      }
+    }
+
+    fn map(&self, addr: &mut AggAddress) {
+      for subnet in addr.src.iter_mut().chain(addr.dst.iter_mut()) {
+        *subnet = self.subnet(subnet.addr);
+      }
+    }
+}
+
+/// Port or inclusive range of ports, serialized as "443" or "8000-8010"
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct PortRange {
+    start: u16,
+    end: u16
+}
+
+impl PortRange {
+    fn single(port: u16) -> PortRange {
+      PortRange { start: port, end: port }
+    }
+
+    fn contains(&self, port: u16) -> bool {
+      port >= self.start && port <= self.end
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+      let (start, end) = s.trim().split_once('-').unwrap_or((s.trim(), s.trim()));
+      match (start.parse(), end.parse()) {
+        (Ok(start), Ok(end)) if start <= end => Ok(PortRange { start, end }),
+        _ => Err(format!("invalid port or port range {}", s))
+      }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+      String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+      if self.start == self.end {
+        write!(f, "{}", self.start)
+      } else {
+        write!(f, "{}-{}", self.start, self.end)
+      }
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+      serializer.collect_str(self)
+    }
+}
+
+/// When sorted ports are written as ranges
+#[derive(Clone, Copy, Debug)]
+struct PortRanges {
+    /// Minimum number of ports in a range, 0 never writes ranges
+    min: u16,
+    /// Maximum distance between neighbouring ports of a range, 1 only joins consecutive ports
+    gap: u16
+}
+
+/// Default port ranges (--port-range-min, --port-range-gap)
+static PORT_RANGES: OnceCell<PortRanges> = OnceCell::new();
+
+/// Adds the run of ports as a range if it has at least `min` ports, as single ports otherwise
+fn push_port_run(result: &mut Vec<PortRange>, run: &mut Vec<PortRange>, end: u16, min: u16) {
+    let count: u32 = run.iter().map(|p| (p.end - p.start) as u32 + 1).sum();
+    if count >= min as u32 {
+      result.push(PortRange { start: run[0].start, end });
+    } else {
+      result.append(run);
+    }
+    run.clear();
+}
+
+/// Sorts the ports numerically and joins runs of at least `min` ports into ranges
+fn collapse_ports(list: &mut Vec<PortRange>, ranges: PortRanges) {
+    list.sort();
+    list.dedup();
+    if ranges.min == 0 || list.is_empty() {
+      return;
+    }
+    let gap = std::cmp::max(ranges.gap, 1) as u32;
+    let mut result: Vec<PortRange> = Vec::with_capacity(list.len());
+    let mut run: Vec<PortRange> = Vec::new();
+    let mut end = 0;
+    for port in list.drain(..) {
+      if !run.is_empty() && port.start as u32 > end as u32 + gap {
+        push_port_run(&mut result, &mut run, end, ranges.min);
+      }
+      end = if run.is_empty() { port.end } else { std::cmp::max(end, port.end) };
+      run.push(port);
+    }
+    push_port_run(&mut result, &mut run, end, ranges.min);
+    *list = result;
+}
+
+/// Address of an aggregated connection, merged values are sorted lists
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+struct AggAddress {
+    src: Vec<Subnet>,
+    dst: Vec<Subnet>,
+    protocol: data::Protocol,
+    port: Vec<PortRange>,
+    vlan: u16
+}
+
+/// Row of the aggregated view and the exports
+#[derive(Serialize, Debug, Clone)]
+struct AggConnection {
+    addr: AggAddress,
+    first_seen: u64,
+    last_seen: u64,
+    hits: u64,
+    max_speed: u32,
+    avg_speed: u32,
+    interfaces: Vec<String>,
+    tunnels: Vec<data::Tunnel>,
+    tx_bytes: u64,
+    rx_bytes: u64,
+    tx_packets: u64,
+    rx_packets: u64,
+    active_time: u64
+}
+
+impl From<data::Connection> for AggConnection {
+    fn from(con: data::Connection) -> Self {
+      AggConnection {
+        addr: AggAddress {
+          src: vec![Subnet::host(con.addr.src)],
+          dst: vec![Subnet::host(con.addr.dst)],
+          protocol: con.addr.protocol,
+          port: vec![PortRange::single(con.addr.port)],
+          vlan: con.addr.vlan
+        },
+        first_seen: con.first_seen,
+        last_seen: con.last_seen,
+        hits: con.hits,
+        max_speed: con.max_speed,
+        avg_speed: con.avg_speed,
+        interfaces: con.interfaces,
+        tunnels: con.tunnels,
+        tx_bytes: con.tx_bytes,
+        rx_bytes: con.rx_bytes,
+        tx_packets: con.tx_packets,
+        rx_packets: con.rx_packets,
+        active_time: con.active_time
+      }
+    }
+}
+
+/// Merges times, hits, interfaces, tunnels and traffic counters of an aggregated connection
+fn merge_connection(con0: &mut AggConnection, c: &AggConnection) {
+    con0.first_seen = std::cmp::min(con0.first_seen, c.first_seen);
+    con0.last_seen = std::cmp::max(con0.last_seen, c.last_seen);
+    con0.hits += c.hits;
+    merge_list(&mut con0.interfaces, &c.interfaces);
+    merge_list(&mut con0.tunnels, &c.tunnels);
+    con0.tx_bytes += c.tx_bytes;
+    con0.rx_bytes += c.rx_bytes;
+    con0.tx_packets += c.tx_packets;
+    con0.rx_packets += c.rx_packets;
+    con0.active_time += c.active_time;
+    con0.max_speed = std::cmp::max(con0.max_speed, c.max_speed);
+    con0.avg_speed = average_speed(con0.tx_bytes + con0.rx_bytes, con0.active_time);
+}
+
+#[get("/con")]
+async fn connections(query: web::Query<ConQuery>, page: web::Query<PageQuery>) -> impl Responder {
+    let mut cons = get_connections(&query);
+    let total = page.apply(&mut cons);
+    HttpResponse::Ok().insert_header(("X-Total-Count", total)).json(cons)
+}
+
+/// Removes the connections matching the filters, at least one filter is required
+#[delete("/con")]
+async fn delete_connections(query: web::Query<ConQuery>) -> impl Responder {
+    if READ_ONLY.load(Ordering::Relaxed) {
+      return HttpResponse::Forbidden().body("Database is served read-only");
+    }
+    if query.is_empty() {
+      return HttpResponse::BadRequest().body("At least one filter is required, remove the database file to delete everything");
+    }
+    let cons = get_connections(&query);
+    let deleted = remove_connections(cons);
+    println!("Deleted {} connections matching {:?}", deleted, query);
+    HttpResponse::Ok().json(DeleteResult { deleted })
+}
+
+/// Address field the aggregated connections are merged by
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AggField {
+    Src,
+    Dst,
+    Port
+}
+
+impl std::str::FromStr for AggField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+      match s.trim() {
+        "src" => Ok(AggField::Src),
+        "dst" => Ok(AggField::Dst),
+        "port" => Ok(AggField::Port),
+        _ => Err(format!("unknown aggregation field {}, expected src, dst or port", s))
+      }
+    }
+}
+
+impl AggField {
+    /// Empties the field, the rest of the address is the group key
+    fn clear(self, addr: &mut AggAddress) {
+      match self {
+        AggField::Src => addr.src.clear(),
+        AggField::Dst => addr.dst.clear(),
+        AggField::Port => addr.port.clear()
+      }
+    }
+
+    fn merge(self, addr: &mut AggAddress, other: &AggAddress) {
+      match self {
+        AggField::Src => addr.src.extend_from_slice(&other.src),
+        AggField::Dst => addr.dst.extend_from_slice(&other.dst),
+        AggField::Port => addr.port.extend_from_slice(&other.port)
+      }
+    }
+
+    fn sort(self, addr: &mut AggAddress) {
+      match self {
+        AggField::Src => { addr.src.sort(); addr.src.dedup(); },
+        AggField::Dst => { addr.dst.sort(); addr.dst.dedup(); },
+        AggField::Port => { addr.port.sort(); addr.port.dedup(); }
+      }
+    }
+}
+
+/// Aggregation parameters of /conagg and /conaggxls
+#[derive(Deserialize, Debug)]
+struct AggQuery {
+    /// Comma separated fields merged one after another, "none" disables aggregation [default: dst,port,src]
+    group: Option<String>,
+    /// Collapse IPv4 addresses into networks of this length (32 merges only complete networks)
+    cidr: Option<u8>,
+    /// Collapse IPv6 addresses into networks of this length
+    cidr6: Option<u8>,
+    /// Comma separated networks shown instead of the addresses inside them, in addition to --network
+    networks: Option<String>,
+    /// Overrides --port-range-min
+    port_range_min: Option<u16>,
+    /// Overrides --port-range-gap
+    port_range_gap: Option<u16>
+}
+
+/// Parsed aggregation parameters
+struct AggOptions {
+    fields: Vec<AggField>,
+    /// None keeps the plain addresses
+    collapse: Option<Collapse>,
+    port_ranges: PortRanges
+}
+
+impl AggQuery {
+    fn options(&self) -> Result<AggOptions, String> {
+      let fields = match self.group.as_deref() {
+        None => vec![AggField::Dst, AggField::Port, AggField::Src],
+        Some("") | Some("none") => Vec::new(),
+        Some(group) => group.split(',').map(|f| f.parse()).collect::<Result<_, _>>()?
+      };
+      let mut collapse = None;
+      if self.cidr.is_some() || self.cidr6.is_some() || self.networks.is_some() {
+        let prefix4 = self.cidr.unwrap_or(32);
+        let prefix6 = self.cidr6.unwrap_or(128);
+        if prefix4 > 32 || prefix6 > 128 {
+          return Err("cidr must be 0-32, cidr6 0-128".to_string());
+        }
+        let mut networks = NETWORKS.get().cloned().unwrap_or_default();
+        if let Some(list) = self.networks.as_deref() {
+          for network in list.split(',').filter(|n| !n.trim().is_empty()) {
+            networks.push(network.parse()?);
+          }
+        }
+        collapse = Some(Collapse { prefix4, prefix6, networks });
+      }
+      let defaults = PORT_RANGES.get().copied().unwrap_or(PortRanges { min: 3, gap: 1 });
+      let port_ranges = PortRanges {
+        min: self.port_range_min.unwrap_or(defaults.min),
+        gap: self.port_range_gap.unwrap_or(defaults.gap)
+      };
+      Ok(AggOptions { fields, collapse, port_ranges })
+    }
+}
+
+/// Merges the connections that differ only in `field`
+fn aggregate(cons: Vec<AggConnection>, field: AggField) -> Vec<AggConnection> {
+    let mut groups: HashMap<AggAddress, usize> = HashMap::new();
+    let mut result: Vec<AggConnection> = Vec::new();
+    for c in cons {
+      let mut key = c.addr.clone();
+      field.clear(&mut key);
+      match groups.get(&key) {
+        Some(&i) => {
+          field.merge(&mut result[i].addr, &c.addr);
+          merge_connection(&mut result[i], &c);
+        },
+        None => {
+          groups.insert(key, result.len());
+          result.push(c);
+        }
+      }
+    }
+    for c in &mut result {
+      field.sort(&mut c.addr);
+    }
+    result
+}
+
+/// Connections merged by the fields in the given order, e.g. by destination first: all destinations
+/// a source reaches on the same port, then all ports between the same sources and destinations
+/// With collapsing the addresses are replaced by their networks before the aggregation,
+/// adjacent networks are merged afterwards. Ports are joined into ranges at the end.
+fn get_connections_agg(query: &ConQuery, options: &AggOptions) -> Vec<AggConnection> {
+    aggregate_connections(get_connections(query).into_iter().map(AggConnection::from).collect(), options)
+}
+
+fn aggregate_connections(mut cons: Vec<AggConnection>, options: &AggOptions) -> Vec<AggConnection> {
+    if let Some(collapse) = &options.collapse {
+      for c in &mut cons {
+        collapse.map(&mut c.addr);
+      }
+    }
+    for field in &options.fields {
+      cons = aggregate(cons, *field);
+    }
+    for c in &mut cons {
+      if options.collapse.is_some() {
+        collapse_subnets(&mut c.addr.src);
+        collapse_subnets(&mut c.addr.dst);
+      }
+      collapse_ports(&mut c.addr.port, options.port_ranges);
+    }
+    cons
+}
+
+#[get("/conagg")]
+async fn connections_agg(query: web::Query<ConQuery>, agg: web::Query<AggQuery>,
+                         page: web::Query<PageQuery>) -> impl Responder {
+    match agg.options() {
+      Ok(options) => {
+        let mut cons = get_connections_agg(&query, &options);
+        let total = page.apply(&mut cons);
+        HttpResponse::Ok().insert_header(("X-Total-Count", total)).json(cons)
+      },
+      Err(e) => HttpResponse::BadRequest().body(e)
+    }
+}
+
+fn join_list<T: ToString>(list: &[T], separator: &str) -> String {
+    list.iter().map(|item| item.to_string()).collect::<Vec<String>>().join(separator)
+}
+
+/// Timezone of the dates in the XLSX reports
+#[derive(Clone, Copy, Debug)]
+enum ReportTimezone {
+    Utc,
+    /// Timezone of the server, including daylight saving changes
+    Local,
+    Fixed(chrono::FixedOffset)
+}
+
+impl std::str::FromStr for ReportTimezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+      match s.trim() {
+        "utc" | "UTC" | "Z" => Ok(ReportTimezone::Utc),
+        "local" => Ok(ReportTimezone::Local),
+        offset => offset.parse().map(ReportTimezone::Fixed)
+          .map_err(|_| format!("invalid timezone {}, expected utc, local or an offset like +03:00", s))
+      }
+    }
+}
+
+impl std::fmt::Display for ReportTimezone {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+      match self {
+        ReportTimezone::Utc => write!(f, "UTC"),
+        ReportTimezone::Local => write!(f, "local time"),
+        ReportTimezone::Fixed(offset) => write!(f, "UTC{}", offset)
+      }
+    }
+}
+
+impl ReportTimezone {
+    /// Excel dates have no timezone, the cell holds the wall clock time of the zone
+    fn excel_time(self, time: u64) -> Result<ExcelDateTime, XlsxError> {
+      let utc: DateTime<Utc> = DateTime::from_timestamp(time as i64, 0).unwrap_or_default();
+      let offset = match self {
+        ReportTimezone::Utc => 0,
+        ReportTimezone::Local => utc.with_timezone(&chrono::Local).offset().local_minus_utc(),
+        ReportTimezone::Fixed(offset) => offset.local_minus_utc()
+      };
+      ExcelDateTime::from_timestamp(utc.timestamp() + offset as i64)
+    }
+}
+
+/// Timezone of the reports (--timezone)
+static TIMEZONE: OnceCell<ReportTimezone> = OnceCell::new();
+
+/// Cell formats shared by the sheets of the report
+struct ReportFormats {
+    header: Format,
+    wrap: Format,
+    date: Format,
+    timezone: ReportTimezone
+}
+
+/// Writes the header row with the column widths, the row stays visible while scrolling
+fn write_header(sheet: &mut Worksheet, header: &[(&str, f64)], formats: &ReportFormats) -> Result<(), XlsxError> {
+    for (col, (name, width)) in header.iter().enumerate() {
+      sheet.write_string_with_format(0, col as u16, *name, &formats.header)?;
+      sheet.set_column_width(col as u16, *width)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    Ok(())
+}
+
+fn write_connection_sheet(sheet: &mut Worksheet, cons: &[AggConnection], formats: &ReportFormats) -> Result<(), XlsxError> {
+    let last_seen = format!("Last seen ({})", formats.timezone);
+    let first_seen = format!("First seen ({})", formats.timezone);
+    write_header(sheet, &[
+      ("Source", 50.0), ("Destination", 50.0), ("Protocol", 10.0), ("Ports", 50.0),
+      (&last_seen, 20.0), ("Interfaces", 30.0), ("VLAN", 8.0), ("Tunnels", 50.0),
+      ("Bytes sent", 14.0), ("Bytes received", 14.0), ("Packets sent", 14.0), ("Packets received", 14.0),
+      ("Avg speed (B/s)", 14.0), ("Max speed (B/s)", 14.0), (&first_seen, 20.0), ("Hits", 10.0)
+    ], formats)?;
+    let mut n: u32 = 1;
+    for con in cons {
+      sheet.write_string_with_format(n, 0, join_list(&con.addr.src, "\n"), &formats.wrap)?;
+      sheet.write_string_with_format(n, 1, join_list(&con.addr.dst, "\n"), &formats.wrap)?;
+      sheet.write_string(n, 2, con.addr.protocol.to_string())?;
+      sheet.write_string_with_format(n, 3, join_list(&con.addr.port, ", "), &formats.wrap)?;
+      sheet.write_datetime_with_format(n, 4, formats.timezone.excel_time(con.last_seen)?, &formats.date)?;
+      sheet.write_string_with_format(n, 5, con.interfaces.join("\n"), &formats.wrap)?;
+      if con.addr.vlan != 0 {
+        sheet.write_number(n, 6, con.addr.vlan)?;
+      }
+      let tunnels: Vec<String> = con.tunnels.iter()
+        .map(|t| format!("{} {} > {} id {}", t.kind, t.src, t.dst, t.id)).collect();
+      sheet.write_string_with_format(n, 7, tunnels.join("\n"), &formats.wrap)?;
+      sheet.write_number(n, 8, con.tx_bytes as f64)?;
+      sheet.write_number(n, 9, con.rx_bytes as f64)?;
+      sheet.write_number(n, 10, con.tx_packets as f64)?;
+      sheet.write_number(n, 11, con.rx_packets as f64)?;
+      sheet.write_number(n, 12, con.avg_speed)?;
+      sheet.write_number(n, 13, con.max_speed)?;
+      sheet.write_datetime_with_format(n, 14, formats.timezone.excel_time(con.first_seen)?, &formats.date)?;
+      sheet.write_number(n, 15, con.hits as f64)?;
+      n += 1;
+    }
+    sheet.autofilter(0, 0, n - 1, 15)?;
+    Ok(())
+}
+
+/// Totals of the connections with the same protocol, port or host
+#[derive(Default)]
+struct SummaryRow {
+    connections: u64,
+    tx_bytes: u64,
+    rx_bytes: u64,
+    hits: u64,
+    first_seen: u64,
+    last_seen: u64
+}
+
+impl SummaryRow {
+    fn add(&mut self, con: &AggConnection) {
+      if self.connections == 0 || con.first_seen < self.first_seen {
+        self.first_seen = con.first_seen;
+      }
+      self.last_seen = self.last_seen.max(con.last_seen);
+      self.connections += 1;
+      self.tx_bytes += con.tx_bytes;
+      self.rx_bytes += con.rx_bytes;
+      self.hits += con.hits;
+    }
+}
+
+/// Totals by protocol, port and host (source or destination), the busiest first in every group
+fn summarize(cons: &[AggConnection]) -> Vec<(&'static str, String, SummaryRow)> {
+    let mut protocols: HashMap<String, SummaryRow> = HashMap::new();
+    let mut ports: HashMap<String, SummaryRow> = HashMap::new();
+    let mut hosts: HashMap<String, SummaryRow> = HashMap::new();
+    for con in cons {
+      protocols.entry(con.addr.protocol.to_string()).or_default().add(con);
+      if con.addr.protocol == data::Protocol::TCP || con.addr.protocol == data::Protocol::UDP {
+        for port in &con.addr.port {
+          ports.entry(format!("{} {}", con.addr.protocol, port)).or_default().add(con);
+        }
+      }
+      let mut addrs: Vec<&Subnet> = con.addr.src.iter().chain(&con.addr.dst).collect();
+      addrs.sort();
+      addrs.dedup();
+      for addr in addrs {
+        hosts.entry(addr.to_string()).or_default().add(con);
+      }
+    }
+    let mut rows = Vec::new();
+    for (group, totals) in [("Protocol", protocols), ("Port", ports), ("Host", hosts)] {
+      let mut totals: Vec<(String, SummaryRow)> = totals.into_iter().collect();
+      totals.sort_by(|a, b| b.1.connections.cmp(&a.1.connections).then_with(|| a.0.cmp(&b.0)));
+      rows.extend(totals.into_iter().map(|(value, row)| (group, value, row)));
+    }
+    rows
+}
+
+fn write_summary_sheet(sheet: &mut Worksheet, cons: &[AggConnection], formats: &ReportFormats) -> Result<(), XlsxError> {
+    let first_seen = format!("First seen ({})", formats.timezone);
+    let last_seen = format!("Last seen ({})", formats.timezone);
+    write_header(sheet, &[
+      ("Group", 12.0), ("Value", 40.0), ("Connections", 14.0), ("Bytes sent", 14.0),
+      ("Bytes received", 14.0), ("Hits", 10.0), (&first_seen, 20.0), (&last_seen, 20.0)
+    ], formats)?;
+    let mut n: u32 = 1;
+    for (group, value, row) in summarize(cons) {
+      sheet.write_string(n, 0, group)?;
+      sheet.write_string(n, 1, value)?;
+      sheet.write_number(n, 2, row.connections as f64)?;
+      sheet.write_number(n, 3, row.tx_bytes as f64)?;
+      sheet.write_number(n, 4, row.rx_bytes as f64)?;
+      sheet.write_number(n, 5, row.hits as f64)?;
+      sheet.write_datetime_with_format(n, 6, formats.timezone.excel_time(row.first_seen)?, &formats.date)?;
+      sheet.write_datetime_with_format(n, 7, formats.timezone.excel_time(row.last_seen)?, &formats.date)?;
+      n += 1;
+    }
+    sheet.autofilter(0, 0, n - 1, 7)?;
+    Ok(())
+}
+
+/// Builds the report in memory: the raw connections, their aggregation and a summary of the raw connections
+fn export_xls(raw: &[AggConnection], agg: &[AggConnection]) -> Result<Vec<u8>, XlsxError> {
+    let formats = ReportFormats {
+      header: Format::new().set_text_wrap().set_background_color(Color::Gray),
+      wrap: Format::new().set_text_wrap(),
+      date: Format::new().set_num_format("yyyy-mm-dd hh:mm:ss"),
+      timezone: TIMEZONE.get().copied().unwrap_or(ReportTimezone::Utc)
+    };
+    let mut workbook = Workbook::new();
+    write_connection_sheet(workbook.add_worksheet().set_name("Connections")?, raw, &formats)?;
+    write_connection_sheet(workbook.add_worksheet().set_name("Aggregated")?, agg, &formats)?;
+    write_summary_sheet(workbook.add_worksheet().set_name("Summary")?, raw, &formats)?;
+    workbook.save_to_buffer()
+}
+
+/// Sends the workbook as a download, export errors are returned as 500
+fn xlsx_response(workbook: Result<Vec<u8>, XlsxError>, name: &str) -> HttpResponse {
+    match workbook {
+      Ok(buffer) => HttpResponse::Ok()
+        .content_type("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet")
+        .insert_header(ContentDisposition {
+          disposition: DispositionType::Attachment,
+          parameters: vec![DispositionParam::Filename(format!("{}.xlsx", name))],
+        })
+        .body(buffer),
+      Err(e) => {
+        println!("Can't export {}.xlsx: {}", name, e);
+        HttpResponse::InternalServerError().body(format!("Can't export {}.xlsx: {}", name, e))
+      }
+    }
+}
+
+/// The page of aggregated connections and all the raw connections matching the filters
+#[get("/conaggxls")]
+async fn connections_agg_xls(query: web::Query<ConQuery>, agg: web::Query<AggQuery>,
+                             page: web::Query<PageQuery>) -> impl Responder {
+    let options = match agg.options() {
+      Ok(options) => options,
+      Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let raw: Vec<AggConnection> = get_connections(&query).into_iter().map(AggConnection::from).collect();
+    let mut cons = aggregate_connections(raw.clone(), &options);
+    page.apply(&mut cons);
+    xlsx_response(export_xls(&raw, &cons), "netracer_agg")
+}
+
+/// The page of raw connections and its aggregation
+#[get("/conxls")]
+async fn connections_xls(query: web::Query<ConQuery>, agg: web::Query<AggQuery>,
+                         page: web::Query<PageQuery>) -> impl Responder {
+    let options = match agg.options() {
+      Ok(options) => options,
+      Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let mut raw = get_connections(&query);
+    page.apply(&mut raw);
+    let raw: Vec<AggConnection> = raw.into_iter().map(AggConnection::from).collect();
+    let cons = aggregate_connections(raw.clone(), &options);
+    xlsx_response(export_xls(&raw, &cons), "netracer")
+}
+
+/// Format of the text exports
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TextFormat {
+    #[default]
+    Csv,
+    Tsv
+}
+
+/// Parameters of the text exports
+#[derive(Deserialize, Debug)]
+struct TextQuery {
+    #[serde(default)]
+    format: TextFormat,
+    /// Comma separated list fields written one value per row: src, dst, port
+    expand: Option<String>
+}
+
+impl TextQuery {
+    fn expand(&self) -> Result<Vec<AggField>, String> {
+      match self.expand.as_deref() {
+        None | Some("") => Ok(Vec::new()),
+        Some(expand) => expand.split(',').map(|f| f.parse()).collect()
+      }
+    }
+}
+
+const TEXT_HEADER: [&str; 16] = ["src", "dst", "protocol", "port", "vlan", "interfaces", "tunnels",
+                                 "first_seen", "last_seen", "hits", "tx_bytes", "rx_bytes",
+                                 "tx_packets", "rx_packets", "avg_speed", "max_speed"];
+
+/// Writes CSV/TSV rows into chunks sent to the HTTP response
+struct TextWriter {
+    format: TextFormat,
+    expand: Vec<AggField>,
+    buf: String,
+    sender: tokio::sync::mpsc::Sender<web::Bytes>
+}
+
+impl TextWriter {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    /// CSV fields are quoted when needed (RFC 4180), TSV has no quoting, tabs and line breaks become spaces
+    fn push_field(&mut self, value: &str) {
+      match self.format {
+        TextFormat::Csv if value.contains([',', '"', '\r', '\n']) => {
+          self.buf.push('"');
+          self.buf.push_str(&value.replace('"', "\"\""));
+          self.buf.push('"');
+        },
+        TextFormat::Csv => self.buf.push_str(value),
+        TextFormat::Tsv => self.buf.push_str(&value.replace(['\t', '\r', '\n'], " "))
+      }
+    }
+
+    /// Returns false if the client is gone
+    fn write_row<T: AsRef<str>>(&mut self, fields: &[T]) -> bool {
+      for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+          self.buf.push(if self.format == TextFormat::Csv { ',' } else { '\t' });
+        }
+        self.push_field(field.as_ref());
+      }
+      self.buf.push_str(if self.format == TextFormat::Csv { "\r\n" } else { "\n" });
+      if self.buf.len() >= TextWriter::CHUNK_SIZE {
+        return self.flush();
+      }
+      true
+    }
+
+    fn flush(&mut self) -> bool {
+      if self.buf.is_empty() {
+        return true;
+      }
+      let chunk = std::mem::take(&mut self.buf);
+      self.sender.blocking_send(web::Bytes::from(chunk)).is_ok()
+    }
+
+    /// One row per value of the expanded fields, other lists are joined with spaces
+    fn write_connection(&mut self, con: &AggConnection) -> bool {
+      let values = |field: AggField, list: Vec<String>| -> Vec<String> {
+        if self.expand.contains(&field) { list } else { vec![list.join(" ")] }
+      };
+      let srcs = values(AggField::Src, con.addr.src.iter().map(|s| s.to_string()).collect());
+      let dsts = values(AggField::Dst, con.addr.dst.iter().map(|s| s.to_string()).collect());
+      let ports = values(AggField::Port, con.addr.port.iter().map(|p| p.to_string()).collect());
+      let tunnels: Vec<String> = con.tunnels.iter()
+        .map(|t| format!("{} {} > {} id {}", t.kind, t.src, t.dst, t.id)).collect();
+      for src in &srcs {
+        for dst in &dsts {
+          for port in &ports {
+            let row = [src.clone(), dst.clone(), con.addr.protocol.to_string(), port.clone(),
+                       con.addr.vlan.to_string(), con.interfaces.join(" "), tunnels.join("; "),
+                       format_iso_time(con.first_seen), format_iso_time(con.last_seen), con.hits.to_string(),
+                       con.tx_bytes.to_string(), con.rx_bytes.to_string(),
+                       con.tx_packets.to_string(), con.rx_packets.to_string(),
+                       con.avg_speed.to_string(), con.max_speed.to_string()];
+            if !self.write_row(&row) {
+              return false;
+            }
+          }
+        }
+      }
+      true
+    }
+}
+
+fn format_iso_time(time: u64) -> String {
+    let datetime: DateTime<Utc> = DateTime::from_timestamp(time as i64, 0).unwrap_or_default();
+    datetime.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Streams the rows written by `produce` in a separate thread, at most a few chunks are buffered
+fn text_response(text: &TextQuery, name: &str,
+                 produce: impl FnOnce(&mut TextWriter) + Send + 'static) -> HttpResponse {
+    let expand = match text.expand() {
+      Ok(expand) => expand,
+      Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let (sender, receiver) = tokio::sync::mpsc::channel::<web::Bytes>(4);
+    let mut writer = TextWriter { format: text.format, expand, buf: String::new(), sender };
+    std::thread::spawn(move || {
+      if writer.write_row(&TEXT_HEADER) {
+        produce(&mut writer);
+        writer.flush();
+      }
+    });
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+      receiver.recv().await.map(|chunk| (Ok::<_, std::convert::Infallible>(chunk), receiver))
+    });
+    let (content_type, extension) = match text.format {
+      TextFormat::Csv => ("text/csv; charset=utf-8", "csv"),
+      TextFormat::Tsv => ("text/tab-separated-values; charset=utf-8", "tsv")
+    };
+    HttpResponse::Ok()
+      .content_type(content_type)
+      .insert_header(ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!("{}.{}", name, extension))],
+      })
+      .streaming(stream)
+}
+
+/// Without sorting the connections are streamed straight from the database
+#[get("/concsv")]
+async fn connections_csv(query: web::Query<ConQuery>, page: web::Query<PageQuery>,
+                         text: web::Query<TextQuery>) -> impl Responder {
+    let query = query.into_inner();
+    let page = page.into_inner();
+    text_response(&text, "netracer", move |writer| {
+      if page.sort.is_some() {
+        let mut cons = get_connections(&query);
+        page.apply(&mut cons);
+        for con in cons {
+          if !writer.write_connection(&AggConnection::from(con)) {
+            return;
+          }
+        }
+        return;
+      }
+      let mut skip = page.offset.unwrap_or(0);
+      let mut left = page.limit.unwrap_or(usize::MAX);
+      scan_connections(&query, |con| {
+        if skip > 0 {
+          skip -= 1;
+          return true;
+        }
+        if left == 0 {
+          return false;
+        }
+        left -= 1;
+        writer.write_connection(&AggConnection::from(con))
+      });
+    })
+}
+
+#[get("/conaggcsv")]
+async fn connections_agg_csv(query: web::Query<ConQuery>, agg: web::Query<AggQuery>,
+                             page: web::Query<PageQuery>, text: web::Query<TextQuery>) -> impl Responder {
+    let options = match agg.options() {
+      Ok(options) => options,
+      Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let query = query.into_inner();
+    let page = page.into_inner();
+    text_response(&text, "netracer_agg", move |writer| {
+      let mut cons = get_connections_agg(&query, &options);
+      page.apply(&mut cons);
+      for con in &cons {
+        if !writer.write_connection(con) {
+          return;
+        }
+      }
+    })
+}
+
+/// Ruleset formats of /conrules
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum RulesFormat {
+    /// nft -f script with an inet table for IPv4 and IPv6
+    #[default]
+    Nft,
+    /// iptables-restore input, IPv4 rules only
+    Iptables,
+    /// ip6tables-restore input, IPv6 rules only
+    Ip6tables
+}
+
+/// Policy of the generated chains, accept only lists the observed traffic
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum RulesPolicy {
+    #[default]
+    Drop,
+    Accept
+}
+
+/// Parameters of the ruleset export
+#[derive(Deserialize, Debug)]
+struct RulesQuery {
+    #[serde(default)]
+    format: RulesFormat,
+    #[serde(default)]
+    policy: RulesPolicy,
+    /// Comma separated addresses of the host the rules are for [default: addresses of the local interfaces]
+    local: Option<String>
+}
+
+/// Comma separated addresses, by default the addresses of the local interfaces
+fn parse_local(local: Option<&str>) -> Result<Vec<IpAddr>, String> {
+    match local {
+      None | Some("") => Ok(pnet::datalink::interfaces().iter()
+        .flat_map(|iface| iface.ips.iter().map(|ip| ip.ip())).collect()),
+      Some(local) => local.split(',')
+        .map(|addr| addr.trim().parse().map_err(|_| format!("invalid address {}", addr)))
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Chain {
+    Input,
+    Forward,
+    Output
+}
+
+impl Chain {
+    const ALL: [Chain; 3] = [Chain::Input, Chain::Forward, Chain::Output];
+
+    fn name(self) -> &'static str {
+      match self {
+        Chain::Input => "input",
+        Chain::Forward => "forward",
+        Chain::Output => "output"
+      }
+    }
+}
+
+/// Accept rule for the part of an aggregated connection passing one chain
+struct AllowRule<'a> {
+    chain: Chain,
+    src: Vec<Subnet>,
+    dst: Vec<Subnet>,
+    con: &'a AggConnection
+}
+
+impl AllowRule<'_> {
+    fn ipv6(&self) -> bool {
+      self.dst.first().is_some_and(|dst| dst.addr.is_ipv6())
+    }
+
+    /// Lowercase protocol name (tcp, udp, gre) or number
+    fn protocol(&self) -> String {
+      self.con.addr.protocol.to_string().to_lowercase()
+    }
+
+    /// Only TCP and UDP rules match ports
+    fn ports(&self) -> &[PortRange] {
+      let protocol = self.con.addr.protocol;
+      if protocol == data::Protocol::TCP || protocol == data::Protocol::UDP {
+        &self.con.addr.port
+      } else {
+        &[]
+      }
+    }
+
+    fn comment(&self) -> String {
+      format!("first seen {}, last seen {}, {} hits", format_iso_time(self.con.first_seen),
+              format_iso_time(self.con.last_seen), self.con.hits)
+    }
+}
+
+/// Connections to local addresses are inbound (input), from local addresses outbound (output),
+/// connections between other hosts are forwarded. An aggregated connection with local and remote
+/// addresses is split between the chains, a connection of the host to itself passes output and input.
+fn allow_rules<'a>(cons: &'a [AggConnection], local: &[IpAddr]) -> Vec<AllowRule<'a>> {
+    let is_local = |subnet: &Subnet| local.iter().any(|ip| subnet.contains(*ip));
+    let mut rules = Vec::new();
+    for con in cons {
+      let (local_src, remote_src): (Vec<Subnet>, Vec<Subnet>) = con.addr.src.iter().partition(|s| is_local(s));
+      let (local_dst, remote_dst): (Vec<Subnet>, Vec<Subnet>) = con.addr.dst.iter().partition(|d| is_local(d));
+      let directions = [
+        (Chain::Input, con.addr.src.clone(), local_dst),
+        (Chain::Forward, remote_src, remote_dst),
+        (Chain::Output, local_src, con.addr.dst.clone())
+      ];
+      for (chain, src, dst) in directions {
+        if !src.is_empty() && !dst.is_empty() {
+          rules.push(AllowRule { chain, src, dst, con });
+        }
+      }
+    }
+    rules
+}
+
+/// Single value or anonymous set of an nft match
+fn nft_set<T: ToString>(list: &[T]) -> String {
+    if list.len() == 1 {
+      list[0].to_string()
+    } else {
+      format!("{{ {} }}", join_list(list, ", "))
+    }
+}
+
+fn nft_rule(rule: &AllowRule) -> String {
+    let family = if rule.ipv6() { "ip6" } else { "ip" };
+    let mut line = format!("{} saddr {} {} daddr {}", family, nft_set(&rule.src), family, nft_set(&rule.dst));
+    if rule.ports().is_empty() {
+      line.push_str(&format!(" meta l4proto {}", rule.protocol()));
+    } else {
+      line.push_str(&format!(" {} dport {}", rule.protocol(), nft_set(rule.ports())));
+    }
+    format!("{} accept comment \"{}\"", line, rule.comment())
+}
+
+/// nft -f script, the netracer table is replaced as a whole
+fn nft_ruleset(rules: &[AllowRule], local: &[IpAddr], policy: RulesPolicy) -> String {
+    let policy = match policy {
+      RulesPolicy::Drop => "drop",
+      RulesPolicy::Accept => "accept"
+    };
+    let mut out = String::from("#!/usr/sbin/nft -f\n");
+    out.push_str(&format!("# Generated by netracer, local addresses: {}\n\n", join_list(local, ", ")));
+    out.push_str("table inet netracer\ndelete table inet netracer\n\ntable inet netracer {\n");
+    for chain in Chain::ALL {
+      let chain_rules: Vec<&AllowRule> = rules.iter().filter(|r| r.chain == chain).collect();
+      if chain == Chain::Forward && chain_rules.is_empty() {
+        continue;
+      }
+      out.push_str(&format!("  chain {} {{\n", chain.name()));
+      out.push_str(&format!("    type filter hook {} priority filter; policy {};\n", chain.name(), policy));
+      out.push_str("    ct state established,related accept\n");
+      match chain {
+        Chain::Input => out.push_str("    iif lo accept\n"),
+        Chain::Output => out.push_str("    oif lo accept\n"),
+        Chain::Forward => {}
+      }
+      out.push_str("    meta l4proto { icmp, ipv6-icmp } accept\n");
+      for rule in chain_rules {
+        out.push_str(&format!("    {}\n", nft_rule(rule)));
+      }
+      out.push_str("  }\n");
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// multiport matches up to 15 ports (a range counts as two), longer lists are split into several rules
+fn iptables_rule(chain: &str, rule: &AllowRule) -> String {
+    let base = format!("-A {} -s {} -d {} -p {}", chain, join_list(&rule.src, ","), join_list(&rule.dst, ","),
+                       rule.protocol());
+    let target = format!("-m comment --comment \"{}\" -j ACCEPT\n", rule.comment());
+    if rule.ports().is_empty() {
+      return format!("{} {}", base, target);
+    }
+    let mut out = String::new();
+    let mut ports: Vec<String> = Vec::new();
+    let mut slots = 0;
+    for port in rule.ports() {
+      let weight = if port.start == port.end { 1 } else { 2 };
+      if slots + weight > 15 {
+        out.push_str(&format!("{} -m multiport --dports {} {}", base, ports.join(","), target));
+        ports.clear();
+        slots = 0;
+      }
+      if port.start == port.end {
+        ports.push(port.start.to_string());
+      } else {
+        ports.push(format!("{}:{}", port.start, port.end));
+      }
+      slots += weight;
+    }
+    out.push_str(&format!("{} -m multiport --dports {} {}", base, ports.join(","), target));
+    out
+}
+
+/// iptables-save style filter table of one address family, restoring it replaces all filter chains
+fn iptables_ruleset(rules: &[AllowRule], local: &[IpAddr], policy: RulesPolicy, ipv6: bool) -> String {
+    let policy = match policy {
+      RulesPolicy::Drop => "DROP",
+      RulesPolicy::Accept => "ACCEPT"
+    };
+    let rules: Vec<&AllowRule> = rules.iter().filter(|r| r.ipv6() == ipv6).collect();
+    let forward = rules.iter().any(|r| r.chain == Chain::Forward);
+    let mut out = format!("# Generated by netracer, local addresses: {}\n*filter\n", join_list(local, ", "));
+    out.push_str(&format!(":INPUT {} [0:0]\n", policy));
+    out.push_str(&format!(":FORWARD {} [0:0]\n", if forward { policy } else { "ACCEPT" }));
+    out.push_str(&format!(":OUTPUT {} [0:0]\n", policy));
+    for chain in Chain::ALL {
+      if chain == Chain::Forward && !forward {
+        continue;
+      }
+      let name = chain.name().to_uppercase();
+      out.push_str(&format!("-A {} -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT\n", name));
+      match chain {
+        Chain::Input => out.push_str("-A INPUT -i lo -j ACCEPT\n"),
+        Chain::Output => out.push_str("-A OUTPUT -o lo -j ACCEPT\n"),
+        Chain::Forward => {}
+      }
+      out.push_str(&format!("-A {} -p {} -j ACCEPT\n", name, if ipv6 { "ipv6-icmp" } else { "icmp" }));
+      for rule in rules.iter().filter(|r| r.chain == chain) {
+        out.push_str(&iptables_rule(&name, rule));
+      }
+    }
+    out.push_str("COMMIT\n");
+    out
+}
+
+/// Allowlist of the aggregated connections for the host with the local addresses
+#[get("/conrules")]
+async fn connections_rules(query: web::Query<ConQuery>, agg: web::Query<AggQuery>,
+                           rules: web::Query<RulesQuery>) -> impl Responder {
+    let options = match agg.options() {
+      Ok(options) => options,
+      Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let local = match parse_local(rules.local.as_deref()) {
+      Ok(local) => local,
+      Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let cons = get_connections_agg(&query, &options);
+    let allow = allow_rules(&cons, &local);
+    let (ruleset, filename) = match rules.format {
+      RulesFormat::Nft => (nft_ruleset(&allow, &local, rules.policy), "netracer.nft"),
+      RulesFormat::Iptables => (iptables_ruleset(&allow, &local, rules.policy, false), "netracer.rules"),
+      RulesFormat::Ip6tables => (iptables_ruleset(&allow, &local, rules.policy, true), "netracer6.rules")
+    };
+    HttpResponse::Ok()
+      .content_type("text/plain; charset=utf-8")
+      .insert_header(ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(filename.to_string())],
+      })
+      .body(ruleset)
+}
+
+/// Parameters of the NetworkPolicy export
+#[derive(Deserialize, Debug)]
+struct PolicyQuery {
+    /// Name of the policy [default: netracer]
+    name: Option<String>,
+    /// Namespace of the policy [default: default]
+    namespace: Option<String>,
+    /// Labels of the pods the policy applies to, e.g. app:billing,tier:api [default: all pods of the namespace]
+    pod: Option<String>,
+    /// Comma separated addresses of the migrated host [default: addresses of the local interfaces]
+    local: Option<String>,
+    /// Peers selected by labels instead of ipBlock, e.g. 10.1.1.5=app:postgres@databases;10.1.2.0/24=app:web
+    labels: Option<String>
+}
+
+/// Pods standing in for the addresses of a network, in the namespace of the policy unless given
+struct PeerSelector {
+    subnet: Subnet,
+    labels: Vec<(String, String)>,
+    namespace: Option<String>
+}
+
+/// RFC 1123 label: namespaces and the parts of a subdomain
+fn is_dns_label(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 63 &&
+      s.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-') &&
+      !s.starts_with('-') && !s.ends_with('-')
+}
+
+/// RFC 1123 subdomain: policy names and label key prefixes
+fn is_dns_subdomain(s: &str) -> bool {
+    s.len() <= 253 && s.split('.').all(is_dns_label)
+}
+
+/// Label value or the name part of a label key: up to 63 alphanumerics, '-', '_' or '.' inside
+fn is_label_name(s: &str) -> bool {
+    s.len() <= 63 &&
+      s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.') &&
+      s.bytes().next().is_none_or(|b| b.is_ascii_alphanumeric()) &&
+      s.bytes().last().is_none_or(|b| b.is_ascii_alphanumeric())
+}
+
+/// Label key: name with an optional subdomain prefix (e.g. app.kubernetes.io/name)
+fn is_label_key(s: &str) -> bool {
+    match s.split_once('/') {
+      Some((prefix, name)) => is_dns_subdomain(prefix) && !name.is_empty() && is_label_name(name),
+      None => !s.is_empty() && is_label_name(s)
+    }
+}
+
+/// key:value pairs separated by commas
+fn parse_labels(s: &str) -> Result<Vec<(String, String)>, String> {
+    s.split(',').map(|label| match label.trim().split_once(':') {
+      Some((key, value)) if !value.is_empty() && is_label_key(key) && is_label_name(value) =>
+        Ok((key.to_string(), value.to_string())),
+      _ => Err(format!("invalid label {}, expected key:value with a Kubernetes label key and value", label))
+    }).collect()
+}
+
+impl std::str::FromStr for PeerSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+      let (subnet, selector) = s.trim().split_once('=')
+        .ok_or_else(|| format!("invalid label mapping {}, expected address=key:value[@namespace]", s))?;
+      let (labels, namespace) = match selector.split_once('@') {
+        Some((_, namespace)) if !is_dns_label(namespace) => return Err(format!("invalid namespace {}", namespace)),
+        Some((labels, namespace)) => (labels, Some(namespace.to_string())),
+        None => (selector, None)
+      };
+      Ok(PeerSelector { subnet: subnet.parse()?, labels: parse_labels(labels)?, namespace })
+    }
+}
+
+impl PolicyQuery {
+    fn selectors(&self) -> Result<Vec<PeerSelector>, String> {
+      match self.labels.as_deref() {
+        None | Some("") => Ok(Vec::new()),
+        Some(labels) => labels.split(';').map(|s| s.parse()).collect()
+      }
+    }
+}
+
+/// matchLabels block, values are quoted as labels like "true" or "8080" are strings
+fn yaml_labels(labels: &[(String, String)], indent: usize) -> String {
+    let mut out = format!("{:indent$}matchLabels:\n", "", indent = indent);
+    for (key, value) in labels {
+      out.push_str(&format!("{:indent$}  {}: \"{}\"\n", "", key, value, indent = indent));
+    }
+    out
+}
+
+/// Peer list items of a rule: the most specific label mapping containing a network, ipBlock otherwise
+fn yaml_peers(subnets: &[Subnet], selectors: &[PeerSelector]) -> Vec<String> {
+    let mut peers: Vec<String> = Vec::new();
+    for subnet in subnets {
+      let selector = selectors.iter()
+        .filter(|s| s.subnet.contains_subnet(subnet))
+        .max_by_key(|s| s.subnet.prefix);
+      let peer = match selector {
+        Some(selector) => {
+          let mut peer = format!("    - podSelector:\n{}", yaml_labels(&selector.labels, 8));
+          if let Some(namespace) = &selector.namespace {
+            peer.push_str("      namespaceSelector:\n");
+            peer.push_str(&yaml_labels(&[("kubernetes.io/metadata.name".to_string(), namespace.clone())], 8));
+          }
+          peer
+        },
+        None => format!("    - ipBlock:\n        cidr: {}/{}\n", subnet.addr, subnet.prefix)
+      };
+      if !peers.contains(&peer) {
+        peers.push(peer);
+      }
+    }
+    peers
+}
+
+/// Ingress (from) or egress (to) rule, NetworkPolicy ports are TCP or UDP only
+fn yaml_policy_rule(rule: &AllowRule, selectors: &[PeerSelector]) -> String {
+    let (peer_key, peers) = match rule.chain {
+      Chain::Input => ("from", yaml_peers(&rule.src, selectors)),
+      _ => ("to", yaml_peers(&rule.dst, selectors))
+    };
+    let protocol = rule.con.addr.protocol.to_string();
+    let mut out = format!("  # {}\n  - {}:\n{}", rule.comment(), peer_key, peers.concat());
+    let ports: Vec<&PortRange> = rule.ports().iter().filter(|p| p.start != 0).collect();
+    if !ports.is_empty() {
+      out.push_str("    ports:\n");
+      for port in ports {
+        out.push_str(&format!("    - protocol: {}\n      port: {}\n", protocol, port.start));
+        if port.end != port.start {
+          out.push_str(&format!("      endPort: {}\n", port.end));
+        }
+      }
+    }
+    out
+}
+
+/// NetworkPolicy allowing the observed inbound (ingress) and outbound (egress) connections of the local addresses.
+/// Forwarded traffic and protocols other than TCP and UDP are left out, cluster DNS is always allowed.
+fn network_policy(rules: &[AllowRule], local: &[IpAddr], query: &PolicyQuery,
+                  selectors: &[PeerSelector]) -> Result<String, String> {
+    let name = query.name.as_deref().unwrap_or("netracer");
+    if !is_dns_subdomain(name) {
+      return Err(format!("invalid policy name {}, expected a DNS subdomain", name));
+    }
+    let namespace = query.namespace.as_deref().unwrap_or("default");
+    if !is_dns_label(namespace) {
+      return Err(format!("invalid namespace {}, expected a DNS label", namespace));
+    }
+    let mut out = format!("# Generated by netracer, local addresses: {}\n", join_list(local, ", "));
+    out.push_str("apiVersion: networking.k8s.io/v1\nkind: NetworkPolicy\nmetadata:\n");
+    out.push_str(&format!("  name: \"{}\"\n", name));
+    out.push_str(&format!("  namespace: \"{}\"\n", namespace));
+    out.push_str("spec:\n");
+    match query.pod.as_deref() {
+      None | Some("") => out.push_str("  podSelector: {}\n"),
+      Some(pod) => out.push_str(&format!("  podSelector:\n{}", yaml_labels(&parse_labels(pod)?, 4)))
+    }
+    out.push_str("  policyTypes:\n  - Ingress\n  - Egress\n");
+    let mut skipped = Vec::new();
+    let mut ingress = String::new();
+    let mut egress = String::from(concat!(
+      "  # cluster DNS\n",
+      "  - to:\n",
+      "    - namespaceSelector:\n",
+      "        matchLabels:\n",
+      "          kubernetes.io/metadata.name: kube-system\n",
+      "      podSelector:\n",
+      "        matchLabels:\n",
+      "          k8s-app: kube-dns\n",
+      "    ports:\n",
+      "    - protocol: UDP\n",
+      "      port: 53\n",
+      "    - protocol: TCP\n",
+      "      port: 53\n"));
+    for rule in rules {
+      let protocol = rule.con.addr.protocol;
+      if protocol != data::Protocol::TCP && protocol != data::Protocol::UDP {
+        skipped.push(format!("# skipped {} {} -> {}\n", protocol, join_list(&rule.src, ", "), join_list(&rule.dst, ", ")));
+        continue;
+      }
+      match rule.chain {
+        Chain::Input => ingress.push_str(&yaml_policy_rule(rule, selectors)),
+        Chain::Output => egress.push_str(&yaml_policy_rule(rule, selectors)),
+        Chain::Forward => {}
+      }
+    }
+    if ingress.is_empty() {
+      out.push_str("  ingress: []\n");
+    } else {
+      out.push_str("  ingress:\n");
+      out.push_str(&ingress);
+    }
+    out.push_str("  egress:\n");
+    out.push_str(&egress);
+    out.push_str(&skipped.concat());
+    Ok(out)
+}
+
+/// Kubernetes NetworkPolicy of the aggregated connections of the local addresses
+#[get("/conpolicy")]
+async fn connections_policy(query: web::Query<ConQuery>, agg: web::Query<AggQuery>,
+                            policy: web::Query<PolicyQuery>) -> impl Responder {
+    let options = match agg.options() {
+      Ok(options) => options,
+      Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let local = match parse_local(policy.local.as_deref()) {
+      Ok(local) => local,
+      Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let selectors = match policy.selectors() {
+      Ok(selectors) => selectors,
+      Err(e) => return HttpResponse::BadRequest().body(e)
+    };
+    let cons = get_connections_agg(&query, &options);
+    let allow = allow_rules(&cons, &local);
+    match network_policy(&allow, &local, &policy, &selectors) {
+      Ok(yaml) => HttpResponse::Ok()
+        .content_type("application/yaml; charset=utf-8")
+        .insert_header(ContentDisposition {
+          disposition: DispositionType::Attachment,
+          parameters: vec![DispositionParam::Filename("netracer-policy.yaml".to_string())],
+        })
+        .body(yaml),
+      Err(e) => HttpResponse::BadRequest().body(e)
+    }
+}
+
+/// Walks IPv6 extension headers up to the upper-layer protocol.
+/// Returns None for non-first fragments and payloads that can't be parsed (e.g. ESP).
+fn ipv6_transport<'a>(ip_packet: &'a Ipv6Packet<'a>) -> Option<(IpNextHeaderProtocol, &'a [u8])> {
+    let mut next = ip_packet.get_next_header();
+    let mut payload = ip_packet.payload();
+    loop {
+      let hdr_len = match next {
+        IpNextHeaderProtocols::Hopopt |
+        IpNextHeaderProtocols::Ipv6Route |
+        IpNextHeaderProtocols::Ipv6Opts |
+        IpNextHeaderProtocols::MobilityHeader |
+        IpNextHeaderProtocols::Hip |
+        IpNextHeaderProtocols::Shim6 => {
+          if payload.len() < 2 { return None; }
+          (payload[1] as usize + 1) * 8
+        },
+        IpNextHeaderProtocols::Ipv6Frag => {
+          if payload.len() < 8 { return None; }
+          // only the first fragment carries the transport header
+          let offset = u16::from_be_bytes([payload[2], payload[3]]) >> 3;
+          if offset != 0 { return None; }
+          8
+        },
+        IpNextHeaderProtocols::Ah => {
+          if payload.len() < 2 { return None; }
+          (payload[1] as usize + 2) * 4
+        },
+        IpNextHeaderProtocols::Esp | IpNextHeaderProtocols::Ipv6NoNxt => return None,
+        _ => return Some((next, payload))
+      };
+      if payload.len() < hdr_len {
+        return None;
+      }
+      next = IpNextHeaderProtocol::new(payload[0]);
+      payload = &payload[hdr_len..];
+    }
+}
+
+/// Where the connection was seen: interface, VLAN and tunnel
+fn connection_location(con: &data::Connection) -> String {
+    let mut location = format!("on {}", con.interfaces.join(", "));
+    if con.addr.vlan != 0 {
+      location = format!("{} vlan {}", location, con.addr.vlan);
+    }
+    for tunnel in &con.tunnels {
+      location = format!("{} via {} {} > {} id {}", location, tunnel.kind, tunnel.src, tunnel.dst, tunnel.id);
+    }
+    location
+}
+
+/// Connection candidate reported by a capture thread
+struct FlowEvent {
+    addr: data::Address,
+    src_port: u16,
+    /// Packet time
+    time: u64,
+    interface: String,
+    tunnel: Option<TunnelInfo>,
+    /// The connection was not known, otherwise it was seen at a new location
+    new: bool
+}
+
+impl FlowEvent {
+    fn connection(&self) -> data::Connection {
+      data::Connection {
+        addr: self.addr,
+        first_seen: self.time,
+        last_seen: self.time,
+        // sessions are counted with the traffic
+        hits: 0,
+        max_speed: 0,
+        avg_speed: 0,
+        interfaces: vec![self.interface.clone()],
+        tunnels: self.tunnel.iter().map(TunnelInfo::record).collect(),
+        tx_bytes: 0,
+        rx_bytes: 0,
+        tx_packets: 0,
+        rx_packets: 0,
+        active_time: 0
+      }
+    }
+}
+
+/// Messages of the database writer
+enum WriterMessage {
+    Flow(FlowEvent),
+    /// Commit everything, flush all counters and reply
+    Sync(std::sync::mpsc::Sender<()>)
+}
+
+/// Queue of the database writer, set when the writer is started
+static WRITER: OnceCell<SyncSender<WriterMessage>> = OnceCell::new();
+/// Flow events waiting in the queue
+static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+/// Flow events dropped because the queue was full
+static EVENTS_DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Flow events received by the writer
+static EVENTS_RECEIVED: AtomicU64 = AtomicU64::new(0);
+/// New connections and locations committed to the database
+static CONNECTIONS_COMMITTED: AtomicU64 = AtomicU64::new(0);
+static BATCH_COMMITS: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the connection candidate and queues new connections and new interfaces (tunnels)
+/// of known ones for the writer. The flow is registered on the capture thread, so the packet
+/// opening it is counted. Live captures never wait for the writer, the event is dropped if
+/// the queue is full and the registration is undone; offline inputs wait instead.
+fn report_flow(src: IpAddr, dst: IpAddr, protocol: data::Protocol, src_port: u16, dst_port: u16,
+               meta: &PacketMeta, opts: &CaptureOptions) {
+    let Some(writer) = WRITER.get() else {
+      return;
+    };
+    let addr = data::Address { src, dst, protocol, port: dst_port, vlan: meta.vlan };
+    let tunnels: Vec<data::Tunnel> = meta.tunnel.iter().map(TunnelInfo::record).collect();
+    let (new, new_interface, new_tunnel) = {
+      let mut flows = FLOWS.lock().unwrap();
+      match flows.get_mut(&addr) {
+        Some(stats) => {
+          let new_interface = merge_list(&mut stats.interfaces, std::slice::from_ref(&opts.interface));
+          let new_tunnel = merge_list(&mut stats.tunnels, &tunnels);
+          if !new_interface && !new_tunnel {
+            return;
+          }
+          (false, new_interface, new_tunnel)
+        },
+        None => {
+          flows.insert(addr, FlowStats {
+            interfaces: vec![opts.interface.clone()],
+            tunnels: tunnels.clone(),
+            ..Default::default()
+          });
+          (true, true, true)
+        }
+      }
+    };
+    let event = FlowEvent {
+      addr,
+      src_port,
+      time: meta.time,
+      interface: opts.interface.clone(),
+      tunnel: meta.tunnel,
+      new
+    };
+    QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+    let sent = if opts.offline {
+      writer.send(WriterMessage::Flow(event)).is_ok()
+    } else {
+      writer.try_send(WriterMessage::Flow(event)).is_ok()
+    };
+    if !sent {
+      QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+      EVENTS_DROPPED.fetch_add(1, Ordering::Relaxed);
+      // the next SYN or datagram reports it again
+      let mut flows = FLOWS.lock().unwrap();
+      if new {
+        flows.remove(&addr);
+      } else if let Some(stats) = flows.get_mut(&addr) {
+        if new_interface {
+          stats.interfaces.retain(|i| i != &opts.interface);
+        }
+        if new_tunnel {
+          stats.tunnels.retain(|t| !tunnels.contains(t));
+        }
+      }
+    }
+}
+
+/// Queues the reported connection for the next commit
+fn accept_flow(event: FlowEvent, pending: &mut HashMap<data::Address, data::Connection>) {
+    let con0 = event.connection();
+    if event.new {
+      println!("New {} connection {} > {} {}", con0.addr.protocol,
+               SocketAddr::new(con0.addr.src, event.src_port),
+               SocketAddr::new(con0.addr.dst, con0.addr.port), connection_location(&con0));
+    } else {
+      println!("{} connection {} > {} seen {}", con0.addr.protocol,
+               SocketAddr::new(con0.addr.src, event.src_port),
+               SocketAddr::new(con0.addr.dst, con0.addr.port), connection_location(&con0));
+    }
+    match pending.get_mut(&con0.addr) {
+      Some(con) => {
+        merge_list(&mut con.interfaces, &con0.interfaces);
+        merge_list(&mut con.tunnels, &con0.tunnels);
+      },
+      None => { pending.insert(con0.addr, con0); }
+    }
+}
+
+/// Stores the pending connections in one transaction, known ones get the new interfaces (tunnels).
+/// The batch is kept and retried with the next commit if the transaction fails.
+fn commit_pending(pending: &mut HashMap<data::Address, data::Connection>) {
+    if pending.is_empty() {
+      return;
+    }
+    let count = pending.len() as u64;
+    let rw = match DB.rw_transaction() {
+      Ok(rw) => rw,
+      Err(e) => {
+        println!("Can't store {} connections, retrying with the next commit: {}", count, e);
+        return;
+      }
+    };
+    for con0 in pending.values() {
+      let con = match rw.get().primary::<data::Connection>(con0.addr) {
+        Ok(Some(mut c)) => {
+          merge_list(&mut c.interfaces, &con0.interfaces);
+          merge_list(&mut c.tunnels, &con0.tunnels);
+          c
+        },
+        _ => con0.clone()
+      };
+      // It's a good practice to use the latest version in your application
+      if let Err(e) = rw.upsert(con) {
+        println!("{}",e);
+      }
+    }
+    match rw.commit() {
+      Ok(_) => {
+        pending.clear();
+        CONNECTIONS_COMMITTED.fetch_add(count, Ordering::Relaxed);
+        BATCH_COMMITS.fetch_add(1, Ordering::Relaxed);
+      },
+      Err(e) => println!("Can't commit {} connections, retrying with the next commit: {}", count, e)
+    }
+}
+
+/// The only thread writing captured connections and traffic to the database:
+/// commits new connections every `commit_interval`, traffic counters every `flush_interval`
+fn run_writer(events: Receiver<WriterMessage>, commit_interval: Duration, flush_interval: Duration) {
+    let mut pending: HashMap<data::Address, data::Connection> = HashMap::new();
+    let mut last_commit = Instant::now();
+    let mut last_flush = Instant::now();
+    loop {
+      match events.recv_timeout(commit_interval.saturating_sub(last_commit.elapsed())) {
+        Ok(WriterMessage::Flow(event)) => {
+          QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+          EVENTS_RECEIVED.fetch_add(1, Ordering::Relaxed);
+          accept_flow(event, &mut pending);
+        },
+        Ok(WriterMessage::Sync(reply)) => {
+          commit_pending(&mut pending);
+          flush_flows(true);
+          last_flush = Instant::now();
+          let _ = reply.send(());
+        },
+        Err(RecvTimeoutError::Timeout) => {},
+        Err(RecvTimeoutError::Disconnected) => break
+      }
+      if last_commit.elapsed() >= commit_interval {
+        commit_pending(&mut pending);
+        last_commit = Instant::now();
+      }
+      if last_flush.elapsed() >= flush_interval {
+        flush_flows(false);
+        last_flush = Instant::now();
+      }
+    }
+}
+
+/// Starts the database writer with a queue of `queue_size` events
+fn start_writer(queue_size: usize, commit_interval: Duration, flush_interval: Duration) {
+    let (sender, receiver) = std::sync::mpsc::sync_channel(queue_size);
+    WRITER.set(sender).unwrap();
+    std::thread::spawn(move || run_writer(receiver, commit_interval, flush_interval));
+}
+
+/// Waits until the queued events are committed and all counters are flushed
+fn sync_writer() {
+    let Some(writer) = WRITER.get() else {
+      return;
+    };
+    let (reply, done) = std::sync::mpsc::channel();
+    if writer.send(WriterMessage::Sync(reply)).is_ok() {
+      let _ = done.recv();
+    }
+}
+
+#[get("/metrics")]
+async fn metrics() -> impl Responder {
+    let flows = FLOWS.lock().unwrap().len();
+    let mut body = String::new();
+    body += "# HELP netracer_queue_depth Flow events waiting for the database writer\n";
+    body += "# TYPE netracer_queue_depth gauge\n";
+    body += &format!("netracer_queue_depth {}\n", QUEUE_DEPTH.load(Ordering::Relaxed));
+    body += "# HELP netracer_events_dropped_total Flow events dropped because the queue was full\n";
+    body += "# TYPE netracer_events_dropped_total counter\n";
+    body += &format!("netracer_events_dropped_total {}\n", EVENTS_DROPPED.load(Ordering::Relaxed));
+    body += "# HELP netracer_events_received_total Flow events processed by the database writer\n";
+    body += "# TYPE netracer_events_received_total counter\n";
+    body += &format!("netracer_events_received_total {}\n", EVENTS_RECEIVED.load(Ordering::Relaxed));
+    body += "# HELP netracer_connections_committed_total New connections and locations written to the database\n";
+    body += "# TYPE netracer_connections_committed_total counter\n";
+    body += &format!("netracer_connections_committed_total {}\n", CONNECTIONS_COMMITTED.load(Ordering::Relaxed));
+    body += "# HELP netracer_batch_commits_total Database transactions of the writer\n";
+    body += "# TYPE netracer_batch_commits_total counter\n";
+    body += &format!("netracer_batch_commits_total {}\n", BATCH_COMMITS.load(Ordering::Relaxed));
+    body += "# HELP netracer_flows Connections tracked in memory\n";
+    body += "# TYPE netracer_flows gauge\n";
+    body += &format!("netracer_flows {}\n", flows);
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body)
+}
+
+/// Traffic of a known connection not written to the database yet
+#[derive(Default)]
+struct FlowStats {
+    tx_bytes: u64,
+    rx_bytes: u64,
+    tx_packets: u64,
+    rx_packets: u64,
+    /// Length of the closed speed windows
+    active_time: u64,
+    /// Maximum speed of the closed speed windows
+    max_speed: u32,
+    /// Packet time the current speed window was opened at
+    window_start: Option<u64>,
+    window_bytes: u64,
+    /// Wall clock time of the last packet, windows of idle flows are closed by the flusher
+    last_packet: u64,
+    /// Packet times of the first packet since the flush and the last packet
+    first_seen: Option<u64>,
+    last_seen: Option<u64>,
+    /// New sessions since the last flush
+    hits: u64,
+    /// Interfaces and tunnels reported to the writer, other locations are reported again
+    interfaces: Vec<String>,
+    tunnels: Vec<data::Tunnel>
+}
+
+impl FlowStats {
+    fn close_window(&mut self, window: u64) {
+      if self.window_start.take().is_some() {
+        let speed = self.window_bytes / window;
+        self.max_speed = std::cmp::max(self.max_speed, u32::try_from(speed).unwrap_or(u32::MAX));
+        self.active_time += window;
+        self.window_bytes = 0;
+      }
+    }
+
+    fn add(&mut self, forward: bool, new_session: bool, len: u64, time: u64, window: u64) {
+      if let Some(start) = self.window_start && time >= start + window {
+        self.close_window(window);
+      }
+      if self.window_start.is_none() {
+        self.window_start = Some(time);
+      }
+      self.window_bytes += len;
+      if forward {
+        self.tx_bytes += len;
+        self.tx_packets += 1;
+      } else {
+        self.rx_bytes += len;
+        self.rx_packets += 1;
+      }
+      self.last_packet = unix_time();
+      if new_session {
+        self.hits += 1;
+      }
+      self.first_seen = Some(self.first_seen.map_or(time, |t| t.min(time)));
+      self.last_seen = Some(self.last_seen.map_or(time, |t| t.max(time)));
+    }
+
+    /// TCP sessions start with SYN, others (UDP, GRE) after an idle period
+    fn is_new_session(&self, protocol: data::Protocol, syn: bool, time: u64) -> bool {
+      if protocol == data::Protocol::TCP {
+        syn
+      } else {
+        self.last_seen.is_none_or(|last| time >= last + SESSION_IDLE_TIMEOUT)
+      }
+    }
+
+    /// Takes the counters to be flushed, the open window stays
+    fn take_counters(&mut self) -> FlowStats {
+      let counters = FlowStats {
+        tx_bytes: self.tx_bytes,
+        rx_bytes: self.rx_bytes,
+        tx_packets: self.tx_packets,
+        rx_packets: self.rx_packets,
+        active_time: self.active_time,
+        max_speed: self.max_speed,
+        first_seen: self.first_seen.take(),
+        last_seen: self.last_seen,
+        hits: self.hits,
+        ..Default::default()
+      };
+      self.hits = 0;
+      self.tx_bytes = 0;
+      self.rx_bytes = 0;
+      self.tx_packets = 0;
+      self.rx_packets = 0;
+      self.active_time = 0;
+      self.max_speed = 0;
+      counters
+    }
+
+    /// Puts back counters taken for a connection that is not committed yet
+    fn restore(&mut self, counters: FlowStats) {
+      self.tx_bytes += counters.tx_bytes;
+      self.rx_bytes += counters.rx_bytes;
+      self.tx_packets += counters.tx_packets;
+      self.rx_packets += counters.rx_packets;
+      self.active_time += counters.active_time;
+      self.max_speed = std::cmp::max(self.max_speed, counters.max_speed);
+      self.hits += counters.hits;
+      if let Some(first_seen) = counters.first_seen {
+        self.first_seen = Some(self.first_seen.map_or(first_seen, |t| t.min(first_seen)));
+      }
+    }
+
+    fn is_empty(&self) -> bool {
+      self.tx_packets == 0 && self.rx_packets == 0 && self.active_time == 0
+    }
+}
+
+/// Known connections with their traffic since the last flush
+static FLOWS: Lazy<Mutex<HashMap<data::Address, FlowStats>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Length of the window the speeds are measured over (seconds)
+static SPEED_WINDOW: AtomicU64 = AtomicU64::new(10);
+/// UDP packets after this idle time (seconds) start a new session
+const SESSION_IDLE_TIMEOUT: u64 = 120;
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Average speed over the windows with traffic (bytes/s)
+fn average_speed(bytes: u64, active_time: u64) -> u32 {
+    if active_time == 0 {
+      return 0;
+    }
+    u32::try_from(bytes / active_time).unwrap_or(u32::MAX)
+}
+
+/// Loads the registered connections so their traffic is counted
+fn load_flows() {
+    let r = DB.r_transaction().unwrap();
+    let binding = r.scan().primary().unwrap();
+    let values: PrimaryScanIterator<data::Connection> = binding.all().unwrap();
+    let mut flows = FLOWS.lock().unwrap();
+    for c in values.flatten() {
+      let stats = flows.entry(c.addr).or_default();
+      stats.last_seen = Some(c.last_seen);
+      stats.interfaces = c.interfaces;
+      stats.tunnels = c.tunnels;
+    }
+}
+
+/// Counts the packet for the registered connection it belongs to, in either direction.
+/// `syn` marks the first packet of a TCP session.
+fn count_packet(src: IpAddr, dst: IpAddr, protocol: data::Protocol, src_port: u16, dst_port: u16, syn: bool, meta: &PacketMeta) {
+    let window = SPEED_WINDOW.load(Ordering::Relaxed);
+    let forward = data::Address {
+      src,
+      dst,
+      protocol,
+      port: dst_port,
+      vlan: meta.vlan
+    };
+    let mut flows = FLOWS.lock().unwrap();
+    if let Some(stats) = flows.get_mut(&forward) {
+      let new_session = stats.is_new_session(protocol, syn, meta.time);
+      stats.add(true, new_session, meta.len as u64, meta.time, window);
+      return;
+    }
+    let reverse = data::Address {
+      src: dst,
+      dst: src,
+      protocol,
+      port: src_port,
+      vlan: meta.vlan
+    };
+    if let Some(stats) = flows.get_mut(&reverse) {
+      stats.add(false, false, meta.len as u64, meta.time, window);
+    }
+}
+
+/// Writes the collected traffic, sessions and times to the database.
+/// Speed windows of idle flows are closed, with `force` all open windows are closed.
+/// Counters of connections waiting for their commit are kept for the next flush.
+fn flush_flows(force: bool) {
+    let window = SPEED_WINDOW.load(Ordering::Relaxed);
+    let now = unix_time();
+    let mut updates: Vec<(data::Address, FlowStats)> = Vec::new();
+    {
+      let mut flows = FLOWS.lock().unwrap();
+      for (addr, stats) in flows.iter_mut() {
+        if force || now >= stats.last_packet + window {
+          stats.close_window(window);
+        }
+        if !stats.is_empty() {
+          updates.push((*addr, stats.take_counters()));
+        }
+      }
+    }
+    if updates.is_empty() {
+      return;
+    }
+    let rw = match DB.rw_transaction() {
+      Ok(rw) => rw,
+      Err(e) => {
+        println!("Can't store traffic counters, retrying with the next flush: {}", e);
+        restore_counters(updates);
+        return;
+      }
+    };
+    let mut written = Vec::new();
+    let mut kept = Vec::new();
+    for (addr, stats) in updates {
+      match rw.get().primary::<data::Connection>(addr) {
+        Ok(Some(mut c)) => {
+          c.tx_bytes += stats.tx_bytes;
+          c.rx_bytes += stats.rx_bytes;
+          c.tx_packets += stats.tx_packets;
+          c.rx_packets += stats.rx_packets;
+          c.active_time += stats.active_time;
+          c.max_speed = std::cmp::max(c.max_speed, stats.max_speed);
+          c.hits += stats.hits;
+          if let Some(first_seen) = stats.first_seen {
+            c.first_seen = std::cmp::min(c.first_seen, first_seen);
+          }
+          if let Some(last_seen) = stats.last_seen {
+            c.last_seen = std::cmp::max(c.last_seen, last_seen);
+          }
+          c.avg_speed = average_speed(c.tx_bytes + c.rx_bytes, c.active_time);
+          match rw.upsert(c) {
+            Ok(_) => written.push((addr, stats)),
+            Err(e) => {
+              println!("{}",e);
+              kept.push((addr, stats));
+            }
+          }
+        },
+        // the connection is still waiting for its commit
+        Ok(None) => kept.push((addr, stats)),
+        Err(e) => {
+          println!("{}",e);
+          kept.push((addr, stats));
+        }
+      }
+    }
+    if let Err(e) = rw.commit() {
+      println!("Can't store traffic counters, retrying with the next flush: {}", e);
+      kept.append(&mut written);
+    }
+    restore_counters(kept);
+}
 
-pub mod data {
-    use native_db::{native_db, ToKey, Key};
-    use native_model::{native_model, Model};
-    use serde::{Deserialize, Serialize};
+/// Returns counters that couldn't be written to their flows for the next flush,
+/// counters of removed connections are dropped with their flows
+fn restore_counters(updates: Vec<(data::Address, FlowStats)>) {
+    let mut flows = FLOWS.lock().unwrap();
+    for (addr, stats) in updates {
+      if let Some(flow) = flows.get_mut(&addr) {
+        flow.restore(stats);
+      }
+    }
+}
 
-    pub type Connection = v1::Connection;
-    pub type Address = v1::Address;
+#[derive(Serialize)]
+struct DeleteResult {
+    deleted: usize
+}
 
-    pub mod v1 {
-        use super::*;         
-        #[derive(Debug, Deserialize, Serialize, Clone)]
-        pub struct Address {
-          pub src: String,
-          pub dst: String,      
-          pub protocol: String,    
-          pub port: String
+/// Removes the connections from the database and stops counting their traffic,
+/// returns the number of removed connections
+fn remove_connections(cons: Vec<data::Connection>) -> usize {
+    if cons.is_empty() {
+      return 0;
+    }
+    let rw = match DB.rw_transaction() {
+      Ok(rw) => rw,
+      Err(e) => {
+        println!("{}",e);
+        return 0;
+      }
+    };
+    let mut addrs = Vec::new();
+    for con in cons {
+      // the writer may have updated the connection since it was read, remove the stored value
+      let addr = con.addr;
+      let current = match rw.get().primary::<data::Connection>(addr) {
+        Ok(Some(c)) => c,
+        Ok(None) => continue,
+        Err(e) => {
+          println!("{}",e);
+          continue;
         }
+      };
+      match rw.remove(current) {
+        Ok(_) => addrs.push(addr),
+        Err(e) => println!("{}",e)
+      }
+    }
+    if let Err(e) = rw.commit() {
+      println!("{}",e);
+      return 0;
+    }
+    let mut flows = FLOWS.lock().unwrap();
+    for addr in &addrs {
+      flows.remove(addr);
+    }
+    addrs.len()
+}
 
-        impl ToKey for Address {
-           fn to_key(&self) -> Key {
-             let keystr = format!("{}_{}_{}_{}",self.src, self.dst, self.protocol, self.port);
-             Key::new(keystr.as_bytes().to_vec())
-           }
+/// Which connections are pruned by the background task
+#[derive(Clone, Default)]
+struct RetentionPolicy {
+    /// Connections not seen for this many days are removed
+    days: Option<u64>,
+    /// Per-protocol overrides of `days`
+    protocol_days: Vec<(data::Protocol, u64)>,
+    /// Only the most recently seen connections are kept
+    max_entries: Option<usize>
+}
 
-           fn key_names() -> Vec<String> {
-             vec!["Address".to_string()]
-           }
-        }
+impl RetentionPolicy {
+    fn is_empty(&self) -> bool {
+      self.days.is_none() && self.protocol_days.is_empty() && self.max_entries.is_none()
+    }
 
+    /// Retention period of the protocol in days
+    fn days(&self, protocol: data::Protocol) -> Option<u64> {
+      self.protocol_days.iter().rev().find(|(p, _)| *p == protocol).map(|(_, days)| *days).or(self.days)
+    }
+}
 
-        #[derive(Serialize, Deserialize, Debug, Clone)]
-        #[native_model(id = 1, version = 1)]
-        #[native_db]
-        pub struct Connection {
-          #[primary_key]
-          pub addr: Address,
-          pub time: u64,
-          pub max_speed: u32,
-          pub avg_speed: u32
+/// Parses PROTOCOL=DAYS of --retention-protocol
+fn parse_protocol_days(s: &str) -> Result<(data::Protocol, u64), String> {
+    let (protocol, days) = s.split_once('=').ok_or("expected PROTOCOL=DAYS, e.g. udp=7")?;
+    let days = days.parse().map_err(|_| format!("invalid number of days {}", days))?;
+    Ok((protocol.parse()?, days))
+}
+
+/// Removes the connections expired by the retention policy
+fn prune_connections(policy: &RetentionPolicy) {
+    let now = unix_time();
+    let mut expired = Vec::new();
+    let mut kept = Vec::new();
+    {
+      let r = DB.r_transaction().unwrap();
+      let binding = r.scan().primary().unwrap();
+      let values: PrimaryScanIterator<data::Connection> = binding.all().unwrap();
+      for c in values.flatten() {
+        match policy.days(c.addr.protocol) {
+          Some(days) if c.last_seen.saturating_add(days.saturating_mul(86400)) < now => expired.push(c),
+          _ => kept.push(c)
         }
+      }
+    }
+    if let Some(max_entries) = policy.max_entries && kept.len() > max_entries {
+      // the least recently seen connections go first
+      kept.sort_by_key(|c| std::cmp::Reverse(c.last_seen));
+      expired.extend(kept.drain(max_entries..));
+    }
+    let count = remove_connections(expired);
+    if count > 0 {
+      println!("Retention: removed {} connections, {} left", count, kept.len());
     }
 }
 
-use once_cell::sync::Lazy;
+/// Handles TCP/UDP payload of an IPv4 or IPv6 packet.
+/// TCP connections are registered by SYN packets only, UDP by the port filters.
+/// With --decap VXLAN, Geneve and GRE payloads are decapsulated as well.
+fn handle_transport(src: IpAddr, dst: IpAddr, protocol: IpNextHeaderProtocol, payload: &[u8],
+                    meta: &PacketMeta, opts: &CaptureOptions) {
+    match protocol {
+      IpNextHeaderProtocols::Tcp => {
+        // Handle TCP packets
+        if let Some(tcp_packet) = TcpPacket::new(payload) {
+          let syn = (tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::SYN) != 0  &&
+                    (tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::ACK) == 0;
+          if syn {
+            report_flow(src, dst, data::Protocol::TCP, tcp_packet.get_source(), tcp_packet.get_destination(), meta, opts);
+          }
+          count_packet(src, dst, data::Protocol::TCP, tcp_packet.get_source(), tcp_packet.get_destination(), syn, meta);
+        }
+      },
+      IpNextHeaderProtocols::Udp => {
+        // Handle UDP packets
+        if let Some(udp_packet) = UdpPacket::new(payload) {
+          let src_port = udp_packet.get_source();
+          let dst_port = udp_packet.get_destination();
+          if dst_port <= opts.max_dst_udp_port && src_port >= opts.min_src_udp_port
+          {
+            report_flow(src, dst, data::Protocol::UDP, src_port, dst_port, meta, opts);
+          }
+          count_packet(src, dst, data::Protocol::UDP, src_port, dst_port, false, meta);
+          if opts.decap {
+            let inner = match dst_port {
+              VXLAN_PORT => parse_vxlan(src, dst, udp_packet.payload()),
+              GENEVE_PORT => parse_geneve(src, dst, udp_packet.payload()),
+              _ => None
+            };
+            if let Some((tunnel, ethertype, payload)) = inner {
+              handle_tunnel(tunnel, ethertype, payload, meta, opts);
+            }
+          }
+        }
+      },
+      IpNextHeaderProtocols::Gre if opts.decap => {
+        report_flow(src, dst, data::Protocol::GRE, 0, 0, meta, opts);
+        count_packet(src, dst, data::Protocol::GRE, 0, 0, false, meta);
+        if let Some((tunnel, ethertype, payload)) = parse_gre(src, dst, payload) {
+          handle_tunnel(tunnel, ethertype, payload, meta, opts);
+        }
+      },
+      _ => {
+//         println!("{:?}", protocol);
+      }
+    }
+}
 
-static MODELS: Lazy<Models> = Lazy::new(|| {
-   let mut models = Models::new();
-   // It's a good practice to define the models by specifying the version
-   models.define::<data::v1::Connection>().unwrap();
-   models
-});
+const VXLAN_PORT: u16 = 4789;
+const GENEVE_PORT: u16 = 6081;
+/// Transparent Ethernet bridging, the tunnel carries Ethernet frames
+const ETHERTYPE_TEB: EtherType = EtherType(0x6558);
 
-static DB: Lazy<Database> = Lazy::new(|| {
-  let db =  Builder::new().create(&MODELS,"/var/netracer.ndb").unwrap();
-  db
-});
-        
+/// Nested tunnels decapsulated from a packet, deeper payloads are ignored
+const MAX_TUNNEL_DEPTH: u8 = 2;
 
-#[get("/{filename:.*}")]
-async fn mainpage(req: HttpRequest) -> impl Responder {
-    let mut path = req.match_info().query("filename");
-    println!("{}",path);
-    if path == "" {
-       path = "index.html";
-    }    
-    let file = UI_DIR.get_file(path).unwrap();
-//    let body = file.contents_utf8().unwrap();
-    let body = file.contents();
-    HttpResponse::Ok().body(body)
+/// Passes the tunnel payload on, inner connections are linked to the outer endpoints
+fn handle_tunnel(tunnel: TunnelInfo, ethertype: EtherType, payload: &[u8], meta: &PacketMeta, opts: &CaptureOptions) {
+    if meta.depth >= MAX_TUNNEL_DEPTH {
+      return;
+    }
+    // VLAN of the outer frame belongs to the underlay
+    let meta = PacketMeta {
+      vlan: 0,
+      tunnel: Some(tunnel),
+      depth: meta.depth + 1,
+      ..*meta
+    };
+    if ethertype == ETHERTYPE_TEB {
+      handle_frame(pcap::Linktype::ETHERNET, payload, meta, opts);
+    } else {
+      handle_ethertype(ethertype, payload, meta, opts);
+    }
 }
 
-fn get_connections() -> Vec<data::Connection> {
-    let r = DB.r_transaction().unwrap();
-    let binding = r.scan().primary().unwrap();
-    let mut values: PrimaryScanIterator<data::Connection> = binding.all().unwrap();
-    let mut cons: Vec<data::Connection> = Vec::new();
-    while let Some(con) = values.next() {
-      match con {
-        Ok(c) => cons.push(c),
-        Err(_e) => {}
-      }
+/// Tunnel, protocol of the payload and the payload of a tunnel header
+type Decapsulated<'a> = (TunnelInfo, EtherType, &'a [u8]);
+
+/// VXLAN (RFC 7348): 8 byte header with 24 bit VNI, Ethernet frame follows
+fn parse_vxlan(src: IpAddr, dst: IpAddr, payload: &[u8]) -> Option<Decapsulated<'_>> {
+    if payload.len() < 8 || payload[0] & 0x08 == 0 {
+      return None;
     }
-    cons
+    let vni = u32::from_be_bytes([0, payload[4], payload[5], payload[6]]);
+    Some((TunnelInfo { kind: "VXLAN", src, dst, id: vni }, ETHERTYPE_TEB, &payload[8..]))
 }
 
-#[get("/con")]
-async fn connections() -> impl Responder {
-    let cons = get_connections();
-    HttpResponse::Ok().json(cons)
+/// Geneve (RFC 8926): 8 byte header, variable length options, protocol type of the payload
+fn parse_geneve(src: IpAddr, dst: IpAddr, payload: &[u8]) -> Option<Decapsulated<'_>> {
+    if payload.len() < 8 || payload[0] >> 6 != 0 {
+      return None;
+    }
+    let hdr_len = 8 + (payload[0] & 0x3f) as usize * 4;
+    if payload.len() < hdr_len {
+      return None;
+    }
+    let ethertype = EtherType::new(u16::from_be_bytes([payload[2], payload[3]]));
+    let vni = u32::from_be_bytes([0, payload[4], payload[5], payload[6]]);
+    Some((TunnelInfo { kind: "GENEVE", src, dst, id: vni }, ethertype, &payload[hdr_len..]))
 }
 
-fn get_connections_agg() -> Vec<data::Connection> {
-    let r = DB.r_transaction().unwrap();
-    let binding = r.scan().primary().unwrap();
-    let mut values: PrimaryScanIterator<data::Connection> = binding.all().unwrap();
-    // aggregate by source and port
-    let mut cons: Vec<data::Connection> = Vec::new();
-    while let Some(con) = values.next() {
-      match con {
-        Ok(c) => { 
-           let c1 = c.clone();
-           let mut found = false;
-           for con0 in &mut cons {
-             if con0.addr.port == c.addr.port && 
-                con0.addr.protocol == c.addr.protocol &&
-                con0.addr.src == c.addr.src 
-                {
-                   con0.addr.dst = format!("{} {}",con0.addr.dst, c.addr.dst);
-                   con0.time = std::cmp::max(con0.time, c.time);
-                   found = true;
-                   break;
-                }
-           }
-           if !found {
-             cons.push(c1);
-           }
-        },
-        Err(_e) => {}
+/// GRE (RFC 2784/2890): optional checksum, key and sequence number fields
+fn parse_gre(src: IpAddr, dst: IpAddr, payload: &[u8]) -> Option<Decapsulated<'_>> {
+    if payload.len() < 4 {
+      return None;
+    }
+    let flags = u16::from_be_bytes([payload[0], payload[1]]);
+    // only version 0, PPTP uses enhanced GRE (version 1)
+    if flags & 0x7 != 0 {
+      return None;
+    }
+    let mut hdr_len = 4;
+    if flags & 0x8000 != 0 {
+      hdr_len += 4;
+    }
+    let mut key = 0;
+    if flags & 0x2000 != 0 {
+      if payload.len() < hdr_len + 4 {
+        return None;
       }
+      key = u32::from_be_bytes([payload[hdr_len], payload[hdr_len + 1], payload[hdr_len + 2], payload[hdr_len + 3]]);
+      hdr_len += 4;
     }
+    if flags & 0x1000 != 0 {
+      hdr_len += 4;
+    }
+    if payload.len() < hdr_len {
+      return None;
+    }
+    let ethertype = EtherType::new(u16::from_be_bytes([payload[2], payload[3]]));
+    Some((TunnelInfo { kind: "GRE", src, dst, id: key }, ethertype, &payload[hdr_len..]))
+}
 
-    // aggregate by source and destination
-    let mut cons2: Vec<data::Connection> = Vec::new();
-    for c in cons {
-       let mut found = false;
-       for con0 in &mut cons2 {
-         if con0.addr.src == c.addr.src &&
-            con0.addr.dst == c.addr.dst &&
-            con0.addr.protocol == c.addr.protocol
-            {
-              con0.addr.port = format!("{}, {}", con0.addr.port, c.addr.port);
-              con0.time = std::cmp::max(con0.time, c.time);
-              found = true;
-              break;
-            }
-       }
-       if !found {
-         cons2.push(c);
-       }
+/// Reads packets from a live or offline capture until it is exhausted
+fn process_capture<T: pcap::Activated + ?Sized>(cap: &mut pcap::Capture<T>, opts: &CaptureOptions) {
+    let linktype = cap.get_datalink();
+    if !is_supported_linktype(linktype) {
+      println!("Unsupported link type {} on {}", linktype.get_name().unwrap_or(linktype.0.to_string()), opts.interface);
+      return;
+    }
+    let id = NEXT_CAPTURE_ID.fetch_add(1, Ordering::Relaxed);
+    OPEN_CAPTURES.lock().unwrap().insert(id, (opts.interface.clone(), linktype));
+    let mut filter_version = 0;
+    loop {
+      let version = CAPTURE_FILTER_VERSION.load(Ordering::SeqCst);
+      if version != filter_version {
+        let filter = CAPTURE_FILTER.lock().unwrap().clone();
+        if let Err(e) = cap.filter(&filter) {
+          println!("Can't apply filter \"{}\" on {}: {}", filter, opts.interface, e);
+        }
+        filter_version = version;
+      }
+      let packet = match cap.next() {
+        Ok(packet) => packet,
+        Err(pcap::Error::TimeoutExpired) => continue,
+        Err(pcap::Error::NoMorePackets) => break,
+        Err(e) => {
+          println!("{}", e);
+          break;
+        }
+      };
+//        println!("Received packet with length: {}", packet.header.len);
+      let meta = PacketMeta {
+        time: packet.header.ts.tv_sec as u64,
+        len: packet.header.len,
+        vlan: 0,
+        tunnel: None,
+        depth: 0
+      };
+      handle_frame(linktype, packet.data, meta, opts);
     }
+    OPEN_CAPTURES.lock().unwrap().remove(&id);
+}
 
-    // aggregate by destination and port
-    let mut cons3: Vec<data::Connection> = Vec::new();
-    for c in cons2 {
-       let mut found = false;
-       for con0 in &mut cons3 {
-         if con0.addr.port == c.addr.port && 
-            con0.addr.protocol == c.addr.protocol &&
-            con0.addr.dst == c.addr.dst 
-            {
-              con0.addr.src = format!("{} {}", con0.addr.src, c.addr.src);
-              con0.time = std::cmp::max(con0.time, c.time);
-              found = true;
-              break;
-            }
-       }
-       if !found {
-         cons3.push(c);
+/// DLT_RAW as returned by pcap_datalink() (LINKTYPE_RAW is 101)
+const DLT_RAW: pcap::Linktype = pcap::Linktype(12);
+/// DLT_RAW on OpenBSD
+const DLT_RAW_OPENBSD: pcap::Linktype = pcap::Linktype(14);
+
+fn is_supported_linktype(linktype: pcap::Linktype) -> bool {
+    matches!(linktype,
+      pcap::Linktype::ETHERNET | pcap::Linktype::LINUX_SLL | pcap::Linktype::LINUX_SLL2 |
+      pcap::Linktype::RAW | DLT_RAW | DLT_RAW_OPENBSD | pcap::Linktype::IPV4 | pcap::Linktype::IPV6 |
+      pcap::Linktype::NULL | pcap::Linktype::LOOP)
+}
+
+/// Strips the link layer header and passes the payload on by its ethertype
+fn handle_frame(linktype: pcap::Linktype, data: &[u8], meta: PacketMeta, opts: &CaptureOptions) {
+    match linktype {
+      pcap::Linktype::ETHERNET => {
+        if let Some(ethernet_packet) = EthernetPacket::new(data) {
+          handle_ethertype(ethernet_packet.get_ethertype(), ethernet_packet.payload(), meta, opts);
+        }
+      },
+      // Linux cooked capture (any interface), protocol is the last field of the 16 byte header
+      pcap::Linktype::LINUX_SLL if data.len() >= 16 => {
+        let ethertype = EtherType::new(u16::from_be_bytes([data[14], data[15]]));
+        handle_ethertype(ethertype, &data[16..], meta, opts);
+      },
+      // Linux cooked capture v2, protocol is the first field of the 20 byte header
+      pcap::Linktype::LINUX_SLL2 if data.len() >= 20 => {
+        let ethertype = EtherType::new(u16::from_be_bytes([data[0], data[1]]));
+        handle_ethertype(ethertype, &data[20..], meta, opts);
+      },
+      // Raw IP (tun, WireGuard), IP version is in the first nibble
+      pcap::Linktype::RAW | DLT_RAW | DLT_RAW_OPENBSD | pcap::Linktype::IPV4 | pcap::Linktype::IPV6 => {
+        match data.first().map(|b| b >> 4) {
+          Some(4) => handle_ethertype(EtherTypes::Ipv4, data, meta, opts),
+          Some(6) => handle_ethertype(EtherTypes::Ipv6, data, meta, opts),
+          _ => {}
+        }
+      },
+      // BSD loopback, 4 byte address family in host (NULL) or network (LOOP) byte order
+      pcap::Linktype::NULL | pcap::Linktype::LOOP if data.len() >= 4 => {
+        let mut family = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if family > 0xffff {
+          family = family.swap_bytes();
+        }
+        match family {
+          // AF_INET
+          2 => handle_ethertype(EtherTypes::Ipv4, &data[4..], meta, opts),
+          // AF_INET6 on Linux, NetBSD/OpenBSD, FreeBSD, macOS
+          10 | 24 | 28 | 30 => handle_ethertype(EtherTypes::Ipv6, &data[4..], meta, opts),
+          _ => {}
+        }
+      },
+      _ => {}
+    }
+}
+
+/// VLAN tags stripped from a frame (QinQ and one more), frames with more tags are dropped
+const MAX_VLAN_TAGS: usize = 3;
+
+/// Handles the payload of an Ethernet frame, VLAN tags (802.1Q, QinQ) are stripped
+fn handle_ethertype(mut ethertype: EtherType, mut payload: &[u8], mut meta: PacketMeta, opts: &CaptureOptions) {
+    let mut tags = 0;
+    while matches!(ethertype, EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ) {
+      if tags == MAX_VLAN_TAGS {
+        return;
+      }
+      let Some(vlan_packet) = VlanPacket::new(payload) else {
+        return;
+      };
+      // the innermost tag wins
+      meta.vlan = vlan_packet.get_vlan_identifier();
+      ethertype = vlan_packet.get_ethertype();
+      payload = &payload[VlanPacket::minimum_packet_size()..];
+      tags += 1;
+    }
+    match ethertype {
+       EtherTypes::Ipv4 => {
+          if let Some(ip_packet) = Ipv4Packet::new(payload) {
+            handle_transport(IpAddr::V4(ip_packet.get_source()),
+                             IpAddr::V4(ip_packet.get_destination()),
+                             ip_packet.get_next_level_protocol(),
+                             ip_packet.payload(), &meta, opts);
+          }
+       },
+       EtherTypes::Ipv6 => {
+          if let Some(ip_packet) = Ipv6Packet::new(payload) &&
+             let Some((protocol, payload)) = ipv6_transport(&ip_packet) {
+            handle_transport(IpAddr::V6(ip_packet.get_source()),
+                             IpAddr::V6(ip_packet.get_destination()),
+                             protocol, payload, &meta, opts);
+          }
+       },
+       _ => {
+//                 println!("{:?}", ethertype);
        }
     }
-    cons3
 }
 
-#[get("/conagg")]
-async fn connections_agg() -> impl Responder {
-    let cons = get_connections_agg();
-    HttpResponse::Ok().json(cons)
+#[derive(Deserialize, Serialize, Debug)]
+struct FilterBody {
+    filter: String
 }
 
-fn export_xls(cons: Vec<data::Connection>, filename: &str) {
-    let mut workbook = Workbook::new();
-    let sheet1 = workbook.add_worksheet();    
-    let mut n: u32 = 1;
-    let cell_format: &Format = &Format::new().set_text_wrap();
-    let header_format: &Format = &Format::new().set_text_wrap().set_background_color(Color::Gray);
-    sheet1.set_column_width(0, 50 as f64).unwrap();
-    sheet1.set_column_width(1, 50 as f64).unwrap();
-    sheet1.set_column_width(3, 50 as f64).unwrap();
-    sheet1.set_column_width(4, 50 as f64).unwrap();
-    sheet1.write_string_with_format(0, 0, "Source", header_format).unwrap();
-    sheet1.write_string_with_format(0, 1, "Destination", header_format).unwrap();
-    sheet1.write_string_with_format(0, 2, "Protocol", header_format).unwrap();
-    sheet1.write_string_with_format(0, 3, "Ports", header_format).unwrap();
-    sheet1.write_string_with_format(0, 4, "LastDate", header_format).unwrap();
-    for con in cons {
-      sheet1.write_string_with_format(n, 0, &*str::replace(&*con.addr.src," ","\n"), cell_format).unwrap();
-      sheet1.write_string_with_format(n, 1, &*str::replace(&*con.addr.dst," ","\n"), cell_format).unwrap();
-      sheet1.write_string(n, 2, &*con.addr.protocol).unwrap();
-      sheet1.write_string(n, 3, &*con.addr.port).unwrap();
-      let datetime: DateTime<Utc> = DateTime::from_timestamp(con.time as i64, 0).unwrap();
-      let newdate = datetime.format("%d.%m.%Y %H:%M");
-      let newdate_str = format!("{}",newdate);
-      sheet1.write_string_with_format(n, 4, &*newdate_str, cell_format).unwrap();
-      n = n + 1;
+#[derive(Serialize, Debug)]
+struct FilterError {
+    /// Capture the filter can't be applied to, none if it doesn't compile at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interface: Option<String>,
+    error: String
+}
+
+/// Checks that the BPF expression compiles for the link type
+fn compile_filter(filter: &str, linktype: pcap::Linktype) -> Result<(), String> {
+    if filter.contains('\0') {
+      return Err("filter contains NUL character".to_string());
     }
-    workbook.save(filename).unwrap();    
+    let cap = pcap::Capture::dead(linktype).map_err(|e| e.to_string())?;
+    cap.compile(filter).map(|_| ()).map_err(|e| e.to_string())
 }
 
-#[get("/conaggxls")]
-async fn connections_agg_xls() -> Result<NamedFile, actix_web::Error> {
-    const FILENAME: &str = "/var/netracer_agg.xlsx";
-    let cons = get_connections_agg();
-    export_xls(cons, FILENAME);
-    let file = NamedFile::open(FILENAME).unwrap();
-    Ok(file.use_last_modified(true).set_content_disposition(ContentDisposition {
-            disposition: DispositionType::Attachment,
-            parameters: vec![],
-        }))
+/// Checks that the BPF expression compiles for every running capture (Ethernet if there are none)
+fn check_filter(filter: &str) -> Result<(), Vec<FilterError>> {
+    let captures: Vec<(String, pcap::Linktype)> = OPEN_CAPTURES.lock().unwrap().values().cloned().collect();
+    if captures.is_empty() {
+      return compile_filter(filter, pcap::Linktype::ETHERNET)
+        .map_err(|error| vec![FilterError { interface: None, error }]);
+    }
+    let errors: Vec<FilterError> = captures.into_iter()
+      .filter_map(|(interface, linktype)| compile_filter(filter, linktype).err()
+        .map(|error| FilterError { interface: Some(interface), error }))
+      .collect();
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
 }
 
-#[get("/conxls")]
-async fn connections_xls() -> Result<NamedFile, actix_web::Error> {
-    const FILENAME: &str = "/var/netracer.xlsx";
-    let cons = get_connections();
-    export_xls(cons, FILENAME);
-    let file = NamedFile::open(FILENAME).unwrap();
-    Ok(file.use_last_modified(true).set_content_disposition(ContentDisposition {
-            disposition: DispositionType::Attachment,
-            parameters: vec![],
-        }))
+/// Sets the BPF filter used by all capture threads
+fn set_capture_filter(filter: &str) {
+    *CAPTURE_FILTER.lock().unwrap() = filter.to_string();
+    CAPTURE_FILTER_VERSION.fetch_add(1, Ordering::SeqCst);
+}
+
+#[get("/admin/filter")]
+async fn get_filter() -> impl Responder {
+    let filter = CAPTURE_FILTER.lock().unwrap().clone();
+    HttpResponse::Ok().json(FilterBody { filter })
+}
+
+#[put("/admin/filter")]
+async fn put_filter(body: web::Json<FilterBody>) -> impl Responder {
+    match check_filter(&body.filter) {
+      Ok(_) => {
+        println!("Capture filter changed to \"{}\"", body.filter);
+        set_capture_filter(&body.filter);
+        HttpResponse::Ok().json(body.into_inner())
+      },
+      Err(errors) => HttpResponse::BadRequest().json(errors)
+    }
 }
 
 async fn do_auth(
@@ -317,17 +3325,35 @@ async fn do_auth(
 async fn main() -> Result<(), native_db::db_type::Error> {
     let args: Args = Args::parse();
     println!("{:?}", args);
+    init_paths(&args);
+    if let Some(Command::Migrate { check }) = args.command {
+      run_migrate(check);
+      return Ok(());
+    }
     let tls = args.tls;
     let cert_file = args.cert;
     let key_file = args.key;
 
 
     let authfile: Option<String> = args.authfile;
+    // endpoints changing the database are served only behind authentication or when explicitly allowed
+    let allow_changes = authfile.is_some() || args.allow_changes;
+    if !allow_changes {
+      println!("DELETE /con and PUT /admin/filter are disabled, use --authfile or --allow-changes to enable them");
+    }
 
-    let new_srv = HttpServer::new(move || {App::new().wrap(Cors::permissive()).
+    let new_srv = HttpServer::new(move || {App::new().wrap(Cors::default().allow_any_origin().allowed_methods(vec!["GET"]).allow_any_header().max_age(3600)).
                                      wrap(Condition::new(authfile != None, HttpAuthentication::basic(do_auth))).
                                   service(connections).service(connections_agg).
-                                  service(connections_xls).service(connections_agg_xls).service(mainpage)
+                                  service(connections_xls).service(connections_agg_xls).
+                                  service(connections_csv).service(connections_agg_csv).service(connections_rules).
+                                  service(connections_policy).
+                                  service(metrics).
+                                  service(get_filter).
+                                  configure(|cfg| if allow_changes {
+                                    cfg.service(delete_connections).service(put_filter);
+                                  }).
+                                  service(mainpage)
                                  });
     let srv: actix_web::dev::Server;
     if tls {
@@ -369,146 +3395,616 @@ async fn main() -> Result<(), native_db::db_type::Error> {
     rt::spawn(srv);
     srv_handle.resume().await;
 
+    if args.read_only {
+      check_db_version(&DB);
+      println!("Database {} served read-only, web interface is available", DB_PATH.get().unwrap().display());
+      std::future::pending::<()>().await;
+    }
+
     ctrlc::set_handler(move || {
         println!("received Ctrl+C!");
+        sync_writer();
         process::exit(0);
     })
     .expect("Error setting Ctrl-C handler");
 
-    let interface = args.interface;
-    let max_dst_udp_port = args.max_dst_udp_port; //.unwrap_or(65535);
-    let min_src_udp_port = args.min_src_udp_port; //.unwrap_or(2048);
-    // Open the capture for the given interface
-    let mut cap = pcap::Capture::from_device(interface.as_str()).unwrap()
-        .promisc(true)  // Set the capture mode to promiscuous
-        .snaplen(32000)  // Set the maximum bytes to capture per packet
-        .immediate_mode(true)
-        .open().unwrap();
-
-    // Start capturing packets
-    while let Ok(packet) = cap.next() {
-//        println!("Received packet with length: {}", packet.header.len);
-        // Here, you can add more processing or filtering logic if needed
-        if let Some(ethernet_packet) = EthernetPacket::new(&packet.data) {
-          match ethernet_packet.get_ethertype() {
-             EtherTypes::Ipv4 => {
-                  if let Some(ip_packet) = Ipv4Packet::new(ethernet_packet.payload()) {
-                  match ip_packet.get_next_level_protocol() {
-                    IpNextHeaderProtocols::Tcp => {
-                      // Handle TCP packets
-                      let tcp_packet = TcpPacket::new(ip_packet.payload());
-                      if let Some(tcp_packet) = tcp_packet {
-                         if (tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::SYN) != 0  && 
-                            (tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::ACK) == 0 {
-                            //println!("{:?}", tcp_packet.get_flags());
-                      /*
-                            println!(
-                              "TCP Packet: {}:{} > {}:{}; Seq: {}, Ack: {}",
-                              ip_packet.get_source(),
-                              tcp_packet.get_source(),
-                              ip_packet.get_destination(),
-                              tcp_packet.get_destination(),
-                              tcp_packet.get_sequence(),
-                              tcp_packet.get_acknowledgement()
-                            );
-*/
-                            let con0 = data::Connection {
-                              addr : data::Address {
-                                src: ip_packet.get_source().to_string(),
-                                dst: ip_packet.get_destination().to_string(),
-                                protocol: "TCP".to_string(),
-                                port: tcp_packet.get_destination().to_string()
-                              },
-                              time: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
-                              max_speed: 0,
-                              avg_speed: 0
-                            };
-                              let r = DB.r_transaction()?;
-                              let con: Result<Option<data::Connection>, _> = r.get().primary(con0.clone().addr);
-                              match con {
-                                Ok(v) => match v {
-                                  None => { 
-                                    println!("New TCP connection {}:{} > {}:{}",
-                                       ip_packet.get_source(),
-                                       tcp_packet.get_source(),
-                                       ip_packet.get_destination(),
-                                       tcp_packet.get_destination()
-                                    );
-                                    let rw = DB.rw_transaction()?;
-                                    // It's a good practice to use the latest version in your application
-                                    rw.insert(con0)?;
-                                    rw.commit()?;                                  
-                                  },
-                                  _ => { }
-                                }
-                                Err(e) => println!("{}",e)
-                              }
-                         }	                                          
-                      }
-                    },
-                    IpNextHeaderProtocols::Udp => {
-                      // Handle UDP packets
-                      let udp_packet = UdpPacket::new(ip_packet.payload());
-                      if let Some(udp_packet) = udp_packet {
-/*
-                          println!(
-                              "UDP Packet: {}:{} > {}:{}; Len: {}",
-                              ip_packet.get_source(),
-                              udp_packet.get_source(),
-                              ip_packet.get_destination(),
-                              udp_packet.get_destination(),
-                              udp_packet.get_length()
-                          );
-*/
-                          let src_port = udp_packet.get_source();
-                          let dst_port = udp_packet.get_destination();
-                          if dst_port <=  max_dst_udp_port && src_port >= min_src_udp_port
-                          { 
-                            let con0 = data::Connection {
-                              addr : data::Address {
-                                src: ip_packet.get_source().to_string(),
-                                dst: ip_packet.get_destination().to_string(),
-                                protocol: "UDP".to_string(),
-                                port: udp_packet.get_destination().to_string()
-                              },
-                              time: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
-                              max_speed: 0,
-                              avg_speed: 0
-                            };
-                              let r = DB.r_transaction()?;
-                              let con: Result<Option<data::Connection>, _> = r.get().primary(con0.clone().addr);
-                              match con {
-                                Ok(v) => match v {
-                                  None => { 
-                                    println!("New UDP connection {}:{} > {}:{}",
-                                       ip_packet.get_source(),
-                                       udp_packet.get_source(),
-                                       ip_packet.get_destination(),
-                                       udp_packet.get_destination()
-                                    );
-                                    let rw = DB.rw_transaction()?;
-                                    // It's a good practice to use the latest version in your application
-                                    rw.insert(con0)?;
-                                    rw.commit()?;                                  
-                                  },
-                                  _ => { }
-                                }
-                                Err(e) => println!("{}",e)
-                              }
-                           }
-                      }
-                    },
-                    _ => {
-//                       println!("{:?}", ip_packet);
-                    }
-                  }
-               }                
-             },
-             _ => {
-//                       println!("{:?}", ethernet_packet);
-             }
-          }
-        }
+    migrate_db(&DB);
+
+    SPEED_WINDOW.store(args.speed_window, Ordering::Relaxed);
+    load_flows();
+    start_writer(args.queue_size as usize,
+                 Duration::from_millis(args.commit_interval),
+                 Duration::from_secs(args.flush_interval));
+
+    let policy = RetentionPolicy {
+      days: args.retention_days,
+      protocol_days: args.retention_protocol.clone(),
+      max_entries: args.max_entries
+    };
+    if !policy.is_empty() {
+      let prune_interval = Duration::from_secs(args.prune_interval);
+      std::thread::spawn(move || loop {
+        prune_connections(&policy);
+        std::thread::sleep(prune_interval);
+      });
+    }
+
+    let opts = CaptureOptions {
+      interface: String::new(),
+      offline: false,
+      decap: args.decap,
+      max_dst_udp_port: args.max_dst_udp_port,
+      min_src_udp_port: args.min_src_udp_port
+    };
+
+    if let Some(filter) = &args.filter {
+      if let Err(e) = compile_filter(filter, pcap::Linktype::ETHERNET) {
+        println!("Invalid filter (--filter): {}", e);
+        process::exit(1);
+      }
+      set_capture_filter(filter);
+    }
+
+    // Each interface is captured in its own thread
+    let promisc = args.promisc;
+    let mut captures = Vec::new();
+    for interface in &args.interface {
+      let opts = CaptureOptions { interface: interface.clone(), ..opts.clone() };
+      captures.push(std::thread::spawn(move || {
+        // Open the capture for the given interface
+        let mut cap = pcap::Capture::from_device(opts.interface.as_str()).unwrap()
+            .promisc(promisc)
+            .snaplen(32000)  // Set the maximum bytes to capture per packet
+            .immediate_mode(true)
+            .timeout(1000)  // Wake up periodically to pick up filter changes
+            .open().unwrap();
+
+        // Start capturing packets
+        process_capture(&mut cap, &opts);
+        println!("Capture on {} stopped", opts.interface);
+      }));
+    }
+
+    let mut inputs = args.read.clone();
+    if args.stdin {
+      // libpcap treats "-" as standard input
+      inputs.push("-".to_string());
+    }
+    for file in &inputs {
+      let name = if file == "-" { "stdin" } else { file.as_str() };
+      println!("Reading {}", name);
+      match pcap::Capture::from_file(file) {
+        Ok(mut cap) => {
+          let opts = CaptureOptions { interface: name.to_string(), offline: true, ..opts.clone() };
+          process_capture(&mut cap, &opts);
+          println!("Finished reading {}", name);
+        },
+        Err(e) => println!("Can't read {}: {}", name, e)
+      }
+    }
+
+    for capture in captures {
+      let _ = capture.join();
     }
+    sync_writer();
+    println!("All inputs processed, web interface is still available");
+    std::future::pending::<()>().await;
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subnets(list: &[&str]) -> Vec<Subnet> {
+      list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn subnet_network_and_last_address() {
+      let cases = [
+        ("10.1.2.3/0", "0.0.0.0/0", "255.255.255.255"),
+        ("10.1.2.3/24", "10.1.2.0/24", "10.1.2.255"),
+        ("10.1.2.3/31", "10.1.2.2/31", "10.1.2.3"),
+        ("10.1.2.3/32", "10.1.2.3", "10.1.2.3"),
+        ("2001:db8::1/0", "::/0", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"),
+        ("2001:db8::1/64", "2001:db8::/64", "2001:db8::ffff:ffff:ffff:ffff"),
+        ("2001:db8::1/128", "2001:db8::1", "2001:db8::1"),
+      ];
+      for (input, network, last) in cases {
+        let subnet: Subnet = input.parse().unwrap();
+        assert_eq!(subnet.to_string(), network, "{}", input);
+        assert_eq!(subnet.last(), last.parse::<IpAddr>().unwrap(), "{}", input);
+      }
+    }
+
+    #[test]
+    fn subnet_siblings() {
+      let cases = [
+        ("10.0.0.0/25", "10.0.0.128/25", true),
+        ("10.0.0.128/25", "10.0.1.0/25", false),
+        ("10.0.0.0/24", "10.0.0.0/24", false),
+        ("10.0.0.0/24", "10.0.1.0/25", false),
+        ("0.0.0.0/1", "128.0.0.0/1", true),
+        ("0.0.0.0/0", "::/0", false),
+        ("0.0.0.0/1", "::/1", false),
+        ("::/128", "::1/128", true),
+      ];
+      for (a, b, sibling) in cases {
+        let (a, b): (Subnet, Subnet) = (a.parse().unwrap(), b.parse().unwrap());
+        assert_eq!(a.is_sibling(&b), sibling, "{} {}", a, b);
+        assert_eq!(b.is_sibling(&a), sibling, "{} {}", b, a);
+      }
+    }
+
+    #[test]
+    fn collapse_subnets_cases() {
+      let cases: &[(&[&str], &[&str])] = &[
+        (&["10.0.0.0", "10.0.0.1", "10.0.0.2", "10.0.0.3"], &["10.0.0.0/30"]),
+        (&["10.0.0.3", "10.0.0.1", "10.0.0.2", "10.0.0.0"], &["10.0.0.0/30"]),
+        (&["10.0.0.1", "10.0.0.2"], &["10.0.0.1", "10.0.0.2"]),
+        (&["10.0.0.1", "10.0.0.1"], &["10.0.0.1"]),
+        // a host inside an existing network is dropped, in either order
+        (&["10.0.0.0/24", "10.0.0.77"], &["10.0.0.0/24"]),
+        (&["10.0.0.77", "10.0.0.0/24"], &["10.0.0.0/24"]),
+        // merged halves merge again with their sibling
+        (&["10.0.0.0/25", "10.0.0.128/25", "10.0.1.0/24"], &["10.0.0.0/23"]),
+        (&["10.0.0.0/26", "10.0.0.64/26", "10.0.0.128/25", "10.0.1.0/24", "10.0.3.0/24"],
+         &["10.0.0.0/23", "10.0.3.0/24"]),
+        (&["0.0.0.0/1", "128.0.0.0/1"], &["0.0.0.0/0"]),
+        (&["10.0.0.1", "0.0.0.0/0"], &["0.0.0.0/0"]),
+        (&["255.255.255.254", "255.255.255.255"], &["255.255.255.254/31"]),
+        (&["::1", "::"], &["::/127"]),
+        (&["::/1", "8000::/1"], &["::/0"]),
+        // IPv4 is sorted before IPv6 and the families never merge
+        (&["::/0", "0.0.0.0/0"], &["0.0.0.0/0", "::/0"]),
+        (&["::/1", "0.0.0.0/1"], &["0.0.0.0/1", "::/1"]),
+        (&["::ffff:10.0.0.1", "10.0.0.0"], &["10.0.0.0", "::ffff:10.0.0.1"]),
+        (&[], &[]),
+      ];
+      for (input, expected) in cases {
+        let mut list = subnets(input);
+        collapse_subnets(&mut list);
+        assert_eq!(list, subnets(expected), "{:?}", input);
+      }
+    }
+
+    fn ports(list: &[&str]) -> Vec<PortRange> {
+      list.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn collapse_ports_cases() {
+      // ports, min, gap, result
+      let cases: &[(&[&str], u16, u16, &[&str])] = &[
+        (&["443", "80", "80"], 3, 1, &["80", "443"]),
+        // min=0 only sorts
+        (&["8002", "8000", "8001", "8001"], 0, 1, &["8000", "8001", "8002"]),
+        (&["8000", "8001", "8002"], 3, 1, &["8000-8002"]),
+        (&["80", "8002", "8000", "9000", "8001"], 3, 1, &["80", "8000-8002", "9000"]),
+        // runs shorter than min stay single ports
+        (&["8000", "8001"], 3, 1, &["8000", "8001"]),
+        (&["8000", "8001", "8003", "8004"], 3, 1, &["8000", "8001", "8003", "8004"]),
+        (&["8000", "8001"], 1, 1, &["8000-8001"]),
+        // gap > 1 bridges the missing ports
+        (&["8000", "8010", "8020"], 3, 10, &["8000-8020"]),
+        (&["8000", "8011", "8022"], 3, 10, &["8000", "8011", "8022"]),
+        (&["8000", "8003", "8006", "9000"], 3, 3, &["8000-8006", "9000"]),
+        // existing ranges count with all their ports
+        (&["8000-8010", "8005"], 3, 1, &["8000-8010"]),
+        (&["8000-8001", "8002"], 3, 1, &["8000-8002"]),
+        (&["8000-8010"], 20, 1, &["8000-8010"]),
+        (&["65533", "65535", "65534"], 3, 1, &["65533-65535"]),
+        (&["65535", "1"], 3, 100, &["1", "65535"]),
+        (&["65534", "65535"], 2, u16::MAX, &["65534-65535"]),
+        (&["0", "65535"], 2, u16::MAX, &["0-65535"]),
+        (&[], 3, 1, &[]),
+      ];
+      for (input, min, gap, expected) in cases {
+        let mut list = ports(input);
+        collapse_ports(&mut list, PortRanges { min: *min, gap: *gap });
+        assert_eq!(list, ports(expected), "{:?} min {} gap {}", input, min, gap);
+      }
+    }
+
+    const OUTER_SRC: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OUTER_DST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    /// Packet and the kind, id, payload protocol and payload parsed from it
+    type TunnelCase = (&'static [u8], Option<(&'static str, u32, u16, Vec<u8>)>);
+
+    fn decapsulated(parsed: Option<Decapsulated>) -> Option<(&'static str, u32, u16, Vec<u8>)> {
+      parsed.map(|(tunnel, ethertype, payload)| {
+        assert_eq!((tunnel.src, tunnel.dst), (OUTER_SRC, OUTER_DST));
+        (tunnel.kind, tunnel.id, ethertype.0, payload.to_vec())
+      })
+    }
+
+    #[test]
+    fn vxlan_header() {
+      let cases: &[TunnelCase] = &[
+        (&[0x08, 0, 0, 0, 0x12, 0x34, 0x56, 0, 0xaa, 0xbb], Some(("VXLAN", 0x123456, 0x6558, vec![0xaa, 0xbb]))),
+        (&[0x08, 0, 0, 0, 0, 0, 1, 0], Some(("VXLAN", 1, 0x6558, vec![]))),
+        // the VNI flag is required
+        (&[0x00, 0, 0, 0, 0x12, 0x34, 0x56, 0, 0xaa], None),
+        // truncated header
+        (&[0x08, 0, 0, 0, 0x12, 0x34, 0x56], None),
+        (&[], None),
+      ];
+      for (packet, expected) in cases {
+        assert_eq!(decapsulated(parse_vxlan(OUTER_SRC, OUTER_DST, packet)), *expected, "{:02x?}", packet);
+      }
+    }
+
+    #[test]
+    fn geneve_header() {
+      let cases: &[TunnelCase] = &[
+        (&[0x00, 0, 0x65, 0x58, 0, 0x01, 0x02, 0, 0xaa], Some(("GENEVE", 0x0102, 0x6558, vec![0xaa]))),
+        // 8 bytes of options before an IPv4 payload
+        (&[0x02, 0, 0x08, 0x00, 0, 0, 7, 0, 1, 2, 3, 4, 5, 6, 7, 8, 0x45], Some(("GENEVE", 7, 0x0800, vec![0x45]))),
+        // options longer than the packet
+        (&[0x03, 0, 0x08, 0x00, 0, 0, 7, 0, 1, 2, 3, 4, 5, 6, 7, 8], None),
+        // unknown version
+        (&[0x40, 0, 0x65, 0x58, 0, 0x01, 0x02, 0, 0xaa], None),
+        // truncated header
+        (&[0x00, 0, 0x65, 0x58, 0, 0x01, 0x02], None),
+      ];
+      for (packet, expected) in cases {
+        assert_eq!(decapsulated(parse_geneve(OUTER_SRC, OUTER_DST, packet)), *expected, "{:02x?}", packet);
+      }
+    }
+
+    #[test]
+    fn gre_header() {
+      let cases: &[TunnelCase] = &[
+        (&[0x00, 0x00, 0x08, 0x00, 0x45], Some(("GRE", 0, 0x0800, vec![0x45]))),
+        // key
+        (&[0x20, 0x00, 0x65, 0x58, 1, 2, 3, 4, 0xaa], Some(("GRE", 0x01020304, 0x6558, vec![0xaa]))),
+        // checksum, key and sequence number, the key follows the checksum
+        (&[0xb0, 0x00, 0x86, 0xdd, 0xff, 0xff, 0, 0, 0, 0, 0, 9, 0, 0, 0, 1, 0x60], Some(("GRE", 9, 0x86dd, vec![0x60]))),
+        // checksum and sequence number without key
+        (&[0x90, 0x00, 0x08, 0x00, 0xff, 0xff, 0, 0, 0, 0, 0, 1, 0x45], Some(("GRE", 0, 0x0800, vec![0x45]))),
+        // enhanced GRE (version 1) is skipped
+        (&[0x30, 0x01, 0x88, 0x0b, 0, 0, 0, 0, 0, 0, 0, 0], None),
+        // truncated key, checksum and sequence number
+        (&[0x20, 0x00, 0x08, 0x00, 1, 2, 3], None),
+        (&[0x80, 0x00, 0x08, 0x00, 0xff, 0xff], None),
+        (&[0x30, 0x00, 0x08, 0x00, 1, 2, 3, 4, 0, 0], None),
+        (&[0x00, 0x00, 0x08], None),
+      ];
+      for (packet, expected) in cases {
+        assert_eq!(decapsulated(parse_gre(OUTER_SRC, OUTER_DST, packet)), *expected, "{:02x?}", packet);
+      }
+    }
+
+    /// IPv6 packet with the next header and the payload after the fixed header
+    fn ipv6_packet(next_header: u8, payload: &[u8]) -> Vec<u8> {
+      let mut packet = vec![0x60, 0, 0, 0];
+      packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+      packet.extend_from_slice(&[next_header, 64]);
+      packet.extend_from_slice(&[0; 32]);
+      packet.extend_from_slice(payload);
+      packet
+    }
+
+    #[test]
+    fn ipv6_extension_headers() {
+      let tcp = [0x12, 0x34];
+      let hop_by_hop = [6, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34];
+      // routing header of 16 bytes followed by a destination options header
+      let chained = [60, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                     17, 0, 0, 0, 0, 0, 0, 0, 0x56];
+      let first_fragment = [6, 0, 0x00, 0x01, 0, 0, 0, 1, 0x12, 0x34];
+      let next_fragment = [6, 0, 0x00, 0xb9, 0, 0, 0, 1, 0x12, 0x34];
+      let ah = [6, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0x12, 0x34];
+      // next header, payload, transport protocol and its payload
+      type Ipv6Case<'a> = (u8, &'a [u8], Option<(u8, &'a [u8])>);
+      let cases: &[Ipv6Case] = &[
+        (6, &tcp, Some((6, &tcp))),
+        (0, &hop_by_hop, Some((6, &[0x12, 0x34]))),
+        (43, &chained, Some((17, &[0x56]))),
+        (44, &first_fragment, Some((6, &[0x12, 0x34]))),
+        (44, &next_fragment, None),
+        (51, &ah, Some((6, &[0x12, 0x34]))),
+        (50, &tcp, None),
+        (59, &[], None),
+        // truncated and overlong extension headers
+        (0, &[6], None),
+        (0, &[6, 1, 0, 0, 0, 0, 0, 0, 0x12, 0x34], None),
+        (44, &[6, 0, 0, 0], None),
+        (51, &[6, 4, 0, 0, 0, 0, 0, 0], None),
+      ];
+      for (next_header, payload, expected) in cases {
+        let packet = ipv6_packet(*next_header, payload);
+        let ip_packet = Ipv6Packet::new(&packet).unwrap();
+        let parsed = ipv6_transport(&ip_packet).map(|(protocol, payload)| (protocol.0, payload));
+        assert_eq!(parsed, *expected, "next header {} {:02x?}", next_header, payload);
+      }
+    }
+
+    /// Aggregated connection with port ranges, seen once at the epoch
+    fn agg_con(src: &[&str], dst: &[&str], protocol: data::Protocol, ports: &[(u16, u16)]) -> AggConnection {
+      AggConnection {
+        addr: AggAddress {
+          src: subnets(src),
+          dst: subnets(dst),
+          protocol,
+          port: ports.iter().map(|&(start, end)| PortRange { start, end }).collect(),
+          vlan: 0
+        },
+        first_seen: 0,
+        last_seen: 0,
+        hits: 1,
+        max_speed: 0,
+        avg_speed: 0,
+        interfaces: Vec::new(),
+        tunnels: Vec::new(),
+        tx_bytes: 0,
+        rx_bytes: 0,
+        tx_packets: 0,
+        rx_packets: 0,
+        active_time: 0
+      }
+    }
+
+    fn policy_query(name: Option<&str>, namespace: Option<&str>, pod: Option<&str>) -> PolicyQuery {
+      PolicyQuery {
+        name: name.map(str::to_string),
+        namespace: namespace.map(str::to_string),
+        pod: pod.map(str::to_string),
+        local: None,
+        labels: None
+      }
+    }
+
+    #[test]
+    fn kubernetes_names() {
+      let cases = [
+        ("app", true),
+        ("app.kubernetes.io/name", true),
+        ("example.com/tier", true),
+        ("a-b_c.d", true),
+        ("App", true),
+        ("", false),
+        ("-app", false),
+        ("app-", false),
+        ("app/", false),
+        ("/app", false),
+        ("Example.com/tier", false),
+        ("a/b/c", false),
+        ("app: x", false),
+        ("x\"\ny", false),
+      ];
+      for (key, expected) in cases {
+        assert_eq!(is_label_key(key), expected, "{:?}", key);
+      }
+      assert!(is_label_key(&"a".repeat(63)));
+      assert!(!is_label_key(&"a".repeat(64)));
+      assert!(is_dns_subdomain("netracer.v2"));
+      assert!(!is_dns_subdomain("netracer_v2"));
+      assert!(is_dns_label("kube-system"));
+      assert!(!is_dns_label("kube.system"));
+      assert!(!is_dns_label(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn policy_parameters() {
+      let local: Vec<IpAddr> = vec!["10.0.0.5".parse().unwrap()];
+      // name, namespace, pod labels, peer labels, accepted
+      let cases = [
+        (None, None, None, None, true),
+        (Some("billing.v2"), Some("prod"), Some("app:billing,tier:api"), Some("10.1.1.5=app:postgres@databases"), true),
+        (Some("Billing"), None, None, None, false),
+        (Some("billing\"\nkind: Pod"), None, None, None, false),
+        (None, Some("prod.eu"), None, None, false),
+        (None, None, Some("app:bill ing"), None, false),
+        (None, None, Some("app:\"x\""), None, false),
+        (None, None, Some("app"), None, false),
+        (None, None, None, Some("10.1.1.5=app:postgres@Databases"), false),
+        (None, None, None, Some("10.1.1.5=app:postgres\n  x: y"), false),
+      ];
+      for (name, namespace, pod, labels, accepted) in cases {
+        let mut query = policy_query(name, namespace, pod);
+        query.labels = labels.map(str::to_string);
+        let result = query.selectors().and_then(|selectors| network_policy(&[], &local, &query, &selectors));
+        assert_eq!(result.is_ok(), accepted, "{:?}", query);
+      }
+    }
+
+    #[test]
+    fn network_policy_rules() {
+      let local: Vec<IpAddr> = vec!["10.0.0.5".parse().unwrap()];
+      let cons = [
+        agg_con(&["10.1.0.0/24"], &["10.0.0.5"], data::Protocol::TCP, &[(443, 443)]),
+        agg_con(&["10.0.0.5"], &["10.1.1.5", "10.2.0.1"], data::Protocol::TCP, &[(5432, 5432), (8000, 8010)]),
+        agg_con(&["10.0.0.5"], &["10.3.0.1"], data::Protocol::GRE, &[(0, 0)]),
+        agg_con(&["10.4.0.1"], &["10.5.0.1"], data::Protocol::UDP, &[(53, 53)]),
+      ];
+      let rules = allow_rules(&cons, &local);
+      let selectors = vec!["10.1.1.0/24=app:postgres@databases".parse().unwrap()];
+      let query = policy_query(Some("billing"), Some("prod"), Some("app:billing"));
+      let yaml = network_policy(&rules, &local, &query, &selectors).unwrap();
+      let expected = [
+        "  name: \"billing\"\n  namespace: \"prod\"\n",
+        "  podSelector:\n    matchLabels:\n      app: \"billing\"\n",
+        concat!("  ingress:\n",
+                "  # first seen 1970-01-01T00:00:00Z, last seen 1970-01-01T00:00:00Z, 1 hits\n",
+                "  - from:\n",
+                "    - ipBlock:\n",
+                "        cidr: 10.1.0.0/24\n",
+                "    ports:\n",
+                "    - protocol: TCP\n",
+                "      port: 443\n"),
+        concat!("  - to:\n",
+                "    - podSelector:\n",
+                "        matchLabels:\n",
+                "          app: \"postgres\"\n",
+                "      namespaceSelector:\n",
+                "        matchLabels:\n",
+                "          kubernetes.io/metadata.name: \"databases\"\n",
+                "    - ipBlock:\n",
+                "        cidr: 10.2.0.1/32\n",
+                "    ports:\n",
+                "    - protocol: TCP\n",
+                "      port: 5432\n",
+                "    - protocol: TCP\n",
+                "      port: 8000\n",
+                "      endPort: 8010\n"),
+        "          k8s-app: kube-dns\n",
+        "# skipped GRE 10.0.0.5 -> 10.3.0.1\n",
+      ];
+      for fragment in expected {
+        assert!(yaml.contains(fragment), "{}\nnot in\n{}", fragment, yaml);
+      }
+      // forwarded traffic is left out
+      assert!(!yaml.contains("10.4.0.1") && !yaml.contains("10.5.0.1"), "{}", yaml);
+
+      let yaml = network_policy(&[], &local, &policy_query(None, None, None), &[]).unwrap();
+      assert!(yaml.contains("  podSelector: {}\n  policyTypes:\n  - Ingress\n  - Egress\n  ingress: []\n  egress:\n  # cluster DNS\n"), "{}", yaml);
+    }
+
+    #[test]
+    fn allow_rule_chains() {
+      let local: Vec<IpAddr> = vec!["10.0.0.5".parse().unwrap(), "2001:db8::5".parse().unwrap()];
+      // source, destination, rules as (chain, source, destination)
+      type RuleCase<'a> = (&'a [&'a str], &'a [&'a str], Vec<(Chain, &'a [&'a str], &'a [&'a str])>);
+      type ChainRule = (Chain, Vec<Subnet>, Vec<Subnet>);
+      let cases: Vec<RuleCase> = vec![
+        (&["10.1.0.0/24"], &["10.0.0.5"], vec![(Chain::Input, &["10.1.0.0/24"], &["10.0.0.5"])]),
+        (&["10.0.0.5"], &["10.1.0.1"], vec![(Chain::Output, &["10.0.0.5"], &["10.1.0.1"])]),
+        (&["10.1.0.1"], &["10.2.0.1"], vec![(Chain::Forward, &["10.1.0.1"], &["10.2.0.1"])]),
+        (&["10.0.0.5"], &["10.0.0.5"], vec![
+          (Chain::Input, &["10.0.0.5"], &["10.0.0.5"]),
+          (Chain::Output, &["10.0.0.5"], &["10.0.0.5"])
+        ]),
+        // mixed sources: remote ones are forwarded to the remote destination
+        (&["10.0.0.5", "10.1.0.1"], &["10.0.0.0/24", "10.2.0.1"], vec![
+          (Chain::Input, &["10.0.0.5", "10.1.0.1"], &["10.0.0.0/24"]),
+          (Chain::Forward, &["10.1.0.1"], &["10.2.0.1"]),
+          (Chain::Output, &["10.0.0.5"], &["10.0.0.0/24", "10.2.0.1"])
+        ]),
+        (&["2001:db8:1::/64"], &["2001:db8::5"], vec![(Chain::Input, &["2001:db8:1::/64"], &["2001:db8::5"])]),
+      ];
+      for (src, dst, expected) in cases {
+        let cons = [agg_con(src, dst, data::Protocol::TCP, &[(22, 22)])];
+        let rules: Vec<ChainRule> = allow_rules(&cons, &local).into_iter()
+          .map(|r| (r.chain, r.src, r.dst)).collect();
+        let expected: Vec<ChainRule> = expected.into_iter()
+          .map(|(chain, src, dst)| (chain, subnets(src), subnets(dst))).collect();
+        assert_eq!(rules, expected, "{:?} -> {:?}", src, dst);
+      }
+    }
+
+    #[test]
+    fn iptables_multiport() {
+      let comment = "-m comment --comment \"first seen 1970-01-01T00:00:00Z, last seen 1970-01-01T00:00:00Z, 1 hits\" -j ACCEPT\n";
+      let singles: Vec<(u16, u16)> = (1..=16).map(|p| (p, p)).collect();
+      let mut ranges: Vec<(u16, u16)> = (1..=7).map(|p| (p * 10, p * 10 + 1)).collect();
+      ranges.push((100, 100));
+      ranges.push((200, 201));
+      // protocol, ports, --dports of every rule
+      type PortCase<'a> = (data::Protocol, Vec<(u16, u16)>, Vec<&'a str>);
+      let cases: Vec<PortCase> = vec![
+        (data::Protocol::TCP, vec![(22, 22)], vec!["22"]),
+        (data::Protocol::UDP, vec![(53, 53), (8000, 8010)], vec!["53,8000:8010"]),
+        (data::Protocol::TCP, singles[..15].to_vec(), vec!["1,2,3,4,5,6,7,8,9,10,11,12,13,14,15"]),
+        (data::Protocol::TCP, singles, vec!["1,2,3,4,5,6,7,8,9,10,11,12,13,14,15", "16"]),
+        // a range takes two slots and is not split
+        (data::Protocol::TCP, ranges, vec!["10:11,20:21,30:31,40:41,50:51,60:61,70:71,100", "200:201"]),
+      ];
+      for (protocol, ports, expected) in cases {
+        let cons = [agg_con(&["10.0.0.5"], &["10.1.0.1"], protocol, &ports)];
+        let rules = allow_rules(&cons, &["10.0.0.5".parse().unwrap()]);
+        let name = protocol.to_string().to_lowercase();
+        let expected: String = expected.iter()
+          .map(|dports| format!("-A OUTPUT -s 10.0.0.5 -d 10.1.0.1 -p {} -m multiport --dports {} {}", name, dports, comment))
+          .collect();
+        assert_eq!(iptables_rule("OUTPUT", &rules[0]), expected, "{:?}", ports);
+      }
+      // protocols without ports match the protocol only
+      for protocol in [data::Protocol::GRE, data::v2::Protocol(50)] {
+        let cons = [agg_con(&["10.0.0.5"], &["10.1.0.1"], protocol, &[(0, 0)])];
+        let rules = allow_rules(&cons, &["10.0.0.5".parse().unwrap()]);
+        let name = protocol.to_string().to_lowercase();
+        assert_eq!(iptables_rule("OUTPUT", &rules[0]), format!("-A OUTPUT -s 10.0.0.5 -d 10.1.0.1 -p {} {}", name, comment));
+        assert!(nft_rule(&rules[0]).starts_with(&format!("ip saddr 10.0.0.5 ip daddr 10.1.0.1 meta l4proto {} accept", name)));
+      }
+    }
+
+    #[test]
+    fn rulesets() {
+      let local: Vec<IpAddr> = vec!["10.0.0.5".parse().unwrap(), "2001:db8::5".parse().unwrap()];
+      let cons = [
+        agg_con(&["10.1.0.0/24", "10.2.0.1"], &["10.0.0.5"], data::Protocol::TCP, &[(22, 22), (443, 443)]),
+        agg_con(&["2001:db8::5"], &["2001:db8:1::1"], data::Protocol::UDP, &[(53, 53)]),
+      ];
+      let rules = allow_rules(&cons, &local);
+      let comment = "comment \"first seen 1970-01-01T00:00:00Z, last seen 1970-01-01T00:00:00Z, 1 hits\"";
+
+      let nft = nft_ruleset(&rules, &local, RulesPolicy::Drop);
+      assert_eq!(nft, format!(concat!(
+        "#!/usr/sbin/nft -f\n",
+        "# Generated by netracer, local addresses: 10.0.0.5, 2001:db8::5\n\n",
+        "table inet netracer\ndelete table inet netracer\n\ntable inet netracer {{\n",
+        "  chain input {{\n",
+        "    type filter hook input priority filter; policy drop;\n",
+        "    ct state established,related accept\n",
+        "    iif lo accept\n",
+        "    meta l4proto {{ icmp, ipv6-icmp }} accept\n",
+        "    ip saddr {{ 10.1.0.0/24, 10.2.0.1 }} ip daddr 10.0.0.5 tcp dport {{ 22, 443 }} accept {c}\n",
+        "  }}\n",
+        "  chain output {{\n",
+        "    type filter hook output priority filter; policy drop;\n",
+        "    ct state established,related accept\n",
+        "    oif lo accept\n",
+        "    meta l4proto {{ icmp, ipv6-icmp }} accept\n",
+        "    ip6 saddr 2001:db8::5 ip6 daddr 2001:db8:1::1 udp dport 53 accept {c}\n",
+        "  }}\n",
+        "}}\n"), c = comment));
+
+      // each family gets only its own rules
+      let v4 = iptables_ruleset(&rules, &local, RulesPolicy::Accept, false);
+      assert!(v4.contains(":INPUT ACCEPT [0:0]\n:FORWARD ACCEPT [0:0]\n:OUTPUT ACCEPT [0:0]\n"), "{}", v4);
+      assert!(v4.contains("-A INPUT -s 10.1.0.0/24,10.2.0.1 -d 10.0.0.5 -p tcp -m multiport --dports 22,443 "), "{}", v4);
+      assert!(v4.contains("-A INPUT -p icmp -j ACCEPT\n") && !v4.contains("-s 2001:db8") && !v4.contains("-A FORWARD"), "{}", v4);
+      let v6 = iptables_ruleset(&rules, &local, RulesPolicy::Drop, true);
+      assert!(v6.contains(":INPUT DROP [0:0]\n:FORWARD ACCEPT [0:0]\n:OUTPUT DROP [0:0]\n"), "{}", v6);
+      assert!(v6.contains("-A OUTPUT -s 2001:db8::5 -d 2001:db8:1::1 -p udp -m multiport --dports 53 "), "{}", v6);
+      assert!(v6.contains("-A OUTPUT -p ipv6-icmp -j ACCEPT\n") && !v6.contains("10.1.0.0") && v6.ends_with("COMMIT\n"), "{}", v6);
+
+      // the forward chain is only generated for forwarded traffic
+      let forwarded = [agg_con(&["10.1.0.1"], &["10.2.0.1"], data::Protocol::TCP, &[(80, 80)])];
+      let rules = allow_rules(&forwarded, &local);
+      assert!(nft_ruleset(&rules, &local, RulesPolicy::Drop).contains("  chain forward {\n    type filter hook forward priority filter; policy drop;\n"));
+      let v4 = iptables_ruleset(&rules, &local, RulesPolicy::Drop, false);
+      assert!(v4.contains(":FORWARD DROP [0:0]\n") && v4.contains("-A FORWARD -s 10.1.0.1 -d 10.2.0.1 -p tcp "), "{}", v4);
+    }
+
+    #[test]
+    fn text_fields() {
+      // value, CSV field, TSV field
+      let cases = [
+        ("10.0.0.5", "10.0.0.5", "10.0.0.5"),
+        ("", "", ""),
+        ("eth0, eth1", "\"eth0, eth1\"", "eth0, eth1"),
+        ("say \"hi\"", "\"say \"\"hi\"\"\"", "say \"hi\""),
+        ("line\nbreak", "\"line\nbreak\"", "line break"),
+        ("cr\r\nlf", "\"cr\r\nlf\"", "cr  lf"),
+        ("tab\there", "tab\there", "tab here"),
+      ];
+      let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+      for (value, csv, tsv) in cases {
+        for (format, expected) in [(TextFormat::Csv, csv), (TextFormat::Tsv, tsv)] {
+          let mut writer = TextWriter { format, expand: Vec::new(), buf: String::new(), sender: sender.clone() };
+          writer.push_field(value);
+          assert_eq!(writer.buf, expected, "{:?} {:?}", format, value);
+        }
+      }
+      let mut writer = TextWriter { format: TextFormat::Csv, expand: Vec::new(), buf: String::new(), sender: sender.clone() };
+      assert!(writer.write_row(&["a", "b,c", ""]));
+      assert_eq!(writer.buf, "a,\"b,c\",\r\n");
+      let mut writer = TextWriter { format: TextFormat::Tsv, expand: Vec::new(), buf: String::new(), sender };
+      assert!(writer.write_row(&["a", "b\tc", ""]));
+      assert_eq!(writer.buf, "a\tb c\t\n");
+    }
+}